@@ -0,0 +1,561 @@
+// Breakpoints and a small command language for stepping a Core interactively
+
+use super::disasm;
+use super::error::EmuError;
+use super::memory::Memory;
+use super::registers::Registers;
+use super::symbols;
+use super::watch::{Watchpoint, WatchHit, WatchKind};
+use super::{Core, RunState};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CmpOp {
+	Eq,
+	Ne,
+	Lt,
+	Le,
+	Gt,
+	Ge,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+	pub reg: String,
+	pub op: CmpOp,
+	pub value: u16,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Breakpoint {
+	pub addr: u16,
+	pub bank: Option<usize>, // Only stop with this bank mapped, set for labels in switchable ROM or SRAM
+	pub condition: Option<Condition>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+	Done, // Ran the requested number of instructions
+	Breakpoint(usize),
+	Locked { opcode: u8, pc: u16 },
+	Watchpoint(WatchHit),
+	Halted, // Entered HALT with IME clear, only an enabled interrupt wakes it
+	Stopped, // Entered STOP, only a joypad press wakes it
+	Interrupted,
+}
+
+pub struct Debugger {
+	pub breakpoints: Vec<Breakpoint>,
+	pub quit: bool,
+	pub interrupted: Box<dyn FnMut() -> bool>, // Polled while running, e.g. set from a ^C handler
+}
+
+// How many instructions run between polls of interrupted
+pub const POLL_INTERVAL: usize = 0x1000;
+
+const HELP: &str = "\
+s, step [n]             Run n instructions (default 1)
+c, continue             Run until a breakpoint, lock-up, HALT with IME clear, STOP or ^C
+b, break ADDR [if REG OP VAL]
+                        Break at ADDR, optionally only when REG OP VAL holds (OP is == != < <= > >=)
+bl, breakpoints         List breakpoints
+d, delete N             Remove breakpoint N
+watch r|w|c|a ADDR [END]
+                        Stop after an instruction reads, writes, changes or accesses ADDR (through END)
+wl, watches             List watchpoints
+dw N                    Remove watchpoint N
+r, regs                 Show registers
+set REG VAL             Write a register (a-l, af, bc, de, hl, sp, pc)
+x ADDR [COUNT]          Dump COUNT bytes of memory (default 16)
+w ADDR VAL              Write a byte of memory
+l, list [COUNT]         Disassemble COUNT instructions around PC (default 10)
+q, quit                 Exit
+Addresses and values are hex, counts are decimal. Addresses can also be labels from a .sym file.";
+
+impl CmpOp {
+	fn parse(text:&str) -> Option<CmpOp> {
+		match text {
+			"==" => Some(CmpOp::Eq),
+			"!=" => Some(CmpOp::Ne),
+			"<" => Some(CmpOp::Lt),
+			"<=" => Some(CmpOp::Le),
+			">" => Some(CmpOp::Gt),
+			">=" => Some(CmpOp::Ge),
+			_ => None,
+		}
+	}
+
+	fn apply(self, a:u16, b:u16) -> bool {
+		match self {
+			CmpOp::Eq => a == b,
+			CmpOp::Ne => a != b,
+			CmpOp::Lt => a < b,
+			CmpOp::Le => a <= b,
+			CmpOp::Gt => a > b,
+			CmpOp::Ge => a >= b,
+		}
+	}
+}
+
+impl Condition {
+	pub fn holds(&self, core:&Core) -> bool {
+		match core.reg.read_named(&self.reg) {
+			Some(val) => self.op.apply(val, self.value),
+			None => false,
+		}
+	}
+}
+
+impl Breakpoint {
+	// "bank:addr" when the breakpoint is tied to a bank, otherwise just the address
+	fn location(&self) -> String {
+		match self.bank {
+			Some(bank) => format!("{:02X}:{:04X}", bank, self.addr),
+			None => format!("{:04X}", self.addr),
+		}
+	}
+}
+
+// Hex with an optional $ or 0x prefix
+pub fn parse_hex(text:&str) -> Result<u16, String> {
+	let digits = text.trim_start_matches('$').trim_start_matches("0x");
+	u16::from_str_radix(digits, 16).map_err(|_| format!("Bad hex value {}", text))
+}
+
+// A label from the loaded symbols, or a hex address
+pub fn parse_addr(core:&Core, text:&str) -> Result<u16, String> {
+	parse_location(core, text).map(|(_, addr)| addr)
+}
+
+// Like parse_addr, but keeps the bank of a label that lives in a switchable region
+pub fn parse_location(core:&Core, text:&str) -> Result<(Option<usize>, u16), String> {
+	match core.symbols.find(text) {
+		Some((bank, addr)) if symbols::is_banked(&core.mem, addr) => Ok((Some(bank), addr)),
+		Some((_, addr)) => Ok((None, addr)),
+		None => parse_hex(text).map(|addr| (None, addr)),
+	}
+}
+
+// The instruction at addr with its operands and location labelled
+fn labelled(core:&Core, addr:u16) -> String {
+	let mut ins = disasm::decode(&core.mem, addr);
+	ins.symbolize(&core.mem, &core.symbols);
+	match core.symbols.describe(&core.mem, addr) {
+		Some(label) => format!("{} ; {}", ins, label),
+		None => format!("{}", ins),
+	}
+}
+
+fn parse_count(text:Option<&str>, default:usize) -> Result<usize, String> {
+	match text {
+		Some(text) => text.parse().map_err(|_| format!("Bad count {}", text)),
+		None => Ok(default),
+	}
+}
+
+// Backs up from pc until a linear sweep lands on it, so the instructions before pc decode properly
+pub fn listing_around(mem:&Memory, pc:u16, count:usize) -> Vec<disasm::Instruction> {
+	let before = count / 3;
+	let mut start = pc;
+	for back in (1 ..= (before as u16 * 3)).rev() {
+		let from = pc.wrapping_sub(back);
+		let mut addr = from;
+		let mut seen = 0;
+		while addr < pc && addr >= from {
+			addr = addr.wrapping_add(disasm::decode(mem, addr).len());
+			seen += 1;
+		}
+		if addr == pc && seen <= before {
+			start = from;
+			break;
+		}
+	}
+	let mut listing = Vec::new();
+	let mut addr = start;
+	while listing.len() < count {
+		let ins = disasm::decode(mem, addr);
+		addr = addr.wrapping_add(ins.len());
+		listing.push(ins);
+	}
+	listing
+}
+
+impl Debugger {
+	pub fn create() -> Debugger {
+		Debugger {
+			breakpoints: Vec::new(),
+			quit: false,
+			interrupted: Box::new(|| false)
+		}
+	}
+
+	pub fn add_breakpoint(&mut self, addr:u16, condition:Option<Condition>) -> usize {
+		self.breakpoints.push(Breakpoint { addr, bank: None, condition });
+		self.breakpoints.len() - 1
+	}
+
+	pub fn remove_breakpoint(&mut self, index:usize) -> bool {
+		if index < self.breakpoints.len() {
+			self.breakpoints.remove(index);
+			true
+		} else {
+			false
+		}
+	}
+
+	// The breakpoint that should stop the CPU before the instruction at PC runs
+	pub fn hit(&self, core:&Core) -> Option<usize> {
+		if core.state != RunState::Running {
+			return None;
+		}
+		self.breakpoints.iter().position(|bp| {
+			bp.addr == core.reg.pc
+				&& bp.bank.map_or(true, |bank| symbols::bank_for(&core.mem, bp.addr) == bank)
+				&& match bp.condition {
+					Some(ref cond) => cond.holds(core),
+					None => true,
+				}
+		})
+	}
+
+	// Runs up to limit instructions, or until a breakpoint if limit is None.
+	// The instruction at the starting PC always runs, so continuing off a breakpoint works.
+	pub fn run(&mut self, core:&mut Core, limit:Option<usize>) -> Result<StopReason, EmuError> {
+		let mut count = 0;
+		let mut since_poll = 0;
+		loop {
+			if limit.is_some_and(|limit| count >= limit) {
+				return Ok(StopReason::Done);
+			}
+			since_poll += 1;
+			if since_poll == POLL_INTERVAL {
+				since_poll = 0;
+				if (self.interrupted)() {
+					return Ok(StopReason::Interrupted);
+				}
+			}
+			let before = core.state;
+			core.step()?;
+			count += 1;
+			if let Some(hit) = core.mem.take_watch_hit() {
+				return Ok(StopReason::Watchpoint(hit));
+			}
+			// Only entering the state is reported, so continuing again waits for the wake-up
+			match core.state {
+				RunState::Locked { opcode, pc } => return Ok(StopReason::Locked { opcode, pc }),
+				RunState::Halted if before != RunState::Halted && !core.mem.int.enabled => return Ok(StopReason::Halted),
+				RunState::Stopped if before != RunState::Stopped => return Ok(StopReason::Stopped),
+				_ => {}
+			}
+			if let Some(index) = self.hit(core) {
+				return Ok(StopReason::Breakpoint(index));
+			}
+		}
+	}
+
+	fn describe(&self, core:&Core, reason:StopReason) -> String {
+		let head = match reason {
+			StopReason::Done => String::new(),
+			StopReason::Breakpoint(index) => format!("Breakpoint {} at {:04X}\n", index, core.reg.pc),
+			StopReason::Locked { opcode, pc } => format!("Locked up on opcode {:02X} at {:04X}\n", opcode, pc),
+			StopReason::Watchpoint(hit) => format!("{}\n", hit),
+			StopReason::Halted => "Halted with interrupts disabled\n".to_string(),
+			StopReason::Stopped => "Stopped until a button is pressed\n".to_string(),
+			StopReason::Interrupted => "Interrupted\n".to_string(),
+		};
+		format!("{}{}", head, labelled(core, core.reg.pc))
+	}
+
+	fn parse_breakpoint(&self, core:&Core, args:&[&str]) -> Result<Breakpoint, String> {
+		let (bank, addr) = parse_location(core, args.first().ok_or("Usage: break ADDR [if REG OP VAL]")?)?;
+		let condition = match args.len() {
+			1 => None,
+			5 if args[1] == "if" => {
+				let reg = args[2].to_lowercase();
+				if !Registers::is_name(&reg) {
+					return Err(format!("Unknown register {}", args[2]));
+				}
+				let op = CmpOp::parse(args[3]).ok_or(format!("Unknown comparison {}", args[3]))?;
+				Some(Condition { reg, op, value: parse_hex(args[4])? })
+			}
+			_ => return Err("Usage: break ADDR [if REG OP VAL]".to_string()),
+		};
+		Ok(Breakpoint { addr, bank, condition })
+	}
+
+	// Runs one line of debugger input, returning what to show the user
+	pub fn execute(&mut self, core:&mut Core, line:&str) -> Result<String, String> {
+		let words: Vec<&str> = line.split_whitespace().collect();
+		let (cmd, args) = match words.split_first() {
+			Some((cmd, args)) => (*cmd, args),
+			None => return Ok(String::new()),
+		};
+		match cmd {
+			"s" | "step" => {
+				let count = parse_count(args.first().cloned(), 1)?;
+				let reason = self.run(core, Some(count)).map_err(|e| e.to_string())?;
+				Ok(self.describe(core, reason))
+			}
+			"c" | "continue" => {
+				let reason = self.run(core, None).map_err(|e| e.to_string())?;
+				Ok(self.describe(core, reason))
+			}
+			"b" | "break" => {
+				let bp = self.parse_breakpoint(core, args)?;
+				let reply = format!("Breakpoint {} at {}", self.breakpoints.len(), bp.location());
+				self.breakpoints.push(bp);
+				Ok(reply)
+			}
+			"bl" | "breakpoints" => {
+				let lines: Vec<String> = self.breakpoints.iter().enumerate().map(|(i, bp)| {
+					match bp.condition {
+						Some(ref cond) => format!("{}: {} if {} {:?} {:X}", i, bp.location(), cond.reg, cond.op, cond.value),
+						None => format!("{}: {}", i, bp.location()),
+					}
+				}).collect();
+				Ok(lines.join("\n"))
+			}
+			"d" | "delete" => {
+				let index = parse_count(args.first().cloned(), usize::MAX)?;
+				if self.remove_breakpoint(index) {
+					Ok(format!("Deleted breakpoint {}", index))
+				} else {
+					Err(format!("No breakpoint {}", index))
+				}
+			}
+			"watch" if args.len() == 2 || args.len() == 3 => {
+				let kind = match args[0] {
+					"r" => WatchKind::Read,
+					"w" => WatchKind::Write,
+					"c" => WatchKind::Change,
+					"a" => WatchKind::Access,
+					_ => return Err(format!("Unknown watch kind {}, use r, w, c or a", args[0])),
+				};
+				let start = parse_addr(core, args[1])?;
+				let end = match args.get(2) {
+					Some(end) => parse_addr(core, end)?,
+					None => start,
+				};
+				core.mem.watchpoints.push(Watchpoint { start, end, kind });
+				Ok(format!("Watchpoint {} on {:04X}-{:04X}", core.mem.watchpoints.len() - 1, start, end))
+			}
+			"wl" | "watches" => {
+				let lines: Vec<String> = core.mem.watchpoints.iter().enumerate()
+					.map(|(i, wp)| format!("{}: {:04X}-{:04X} {:?}", i, wp.start, wp.end, wp.kind))
+					.collect();
+				Ok(lines.join("\n"))
+			}
+			"dw" => {
+				let index = parse_count(args.first().cloned(), usize::MAX)?;
+				if index < core.mem.watchpoints.len() {
+					core.mem.watchpoints.remove(index);
+					Ok(format!("Deleted watchpoint {}", index))
+				} else {
+					Err(format!("No watchpoint {}", index))
+				}
+			}
+			"r" | "regs" => Ok(format!("{}\nState: {:?}", core.reg, core.state)),
+			"set" if args.len() == 2 => {
+				let val = parse_hex(args[1])?;
+				if core.reg.write_named(&args[0].to_lowercase(), val) {
+					Ok(format!("{}", core.reg))
+				} else {
+					Err(format!("Unknown register {}", args[0]))
+				}
+			}
+			"x" if !args.is_empty() => {
+				let addr = parse_addr(core, args[0])?;
+				let count = parse_count(args.get(1).cloned(), 16)?;
+				let lines: Vec<String> = (0 .. count).step_by(16).map(|row| {
+					let start = addr.wrapping_add(row as u16);
+					let bytes: Vec<String> = (0 .. (count - row).min(16))
+						.map(|i| format!("{:02X}", core.mem.peek(start.wrapping_add(i as u16))))
+						.collect();
+					format!("{:04X}: {}", start, bytes.join(" "))
+				}).collect();
+				Ok(lines.join("\n"))
+			}
+			"w" if args.len() == 2 => {
+				let addr = parse_addr(core, args[0])?;
+				let val = parse_hex(args[1])?;
+				core.mem.set_mem(addr, val as u8);
+				Ok(format!("{:04X}: {:02X}", addr, core.mem.peek(addr)))
+			}
+			"l" | "list" => {
+				let count = parse_count(args.first().cloned(), 10)?;
+				let mut lines = Vec::new();
+				for mut ins in listing_around(&core.mem, core.reg.pc, count) {
+					if let Some(label) = core.symbols.lookup(&core.mem, ins.addr) {
+						lines.push(format!("{}:", label));
+					}
+					ins.symbolize(&core.mem, &core.symbols);
+					let marker = if ins.addr == core.reg.pc { "=>" } else { "  " };
+					lines.push(format!("{} {}", marker, ins));
+				}
+				Ok(lines.join("\n"))
+			}
+			"h" | "help" => Ok(HELP.to_string()),
+			"q" | "quit" => {
+				self.quit = true;
+				Ok(String::new())
+			}
+			_ => Err(format!("Unknown command {}, try help", line.trim())),
+		}
+	}
+}
+
+mod test {
+	#[cfg(test)]
+	use super::super::test::core_with_program;
+
+	#[test]
+	fn test_breakpoint() {
+		use super::*;
+		// INC A; JR -3
+		let mut core = core_with_program(&[0x3C, 0x18, 0xFD]);
+		let mut debugger = Debugger::create();
+		debugger.execute(&mut core, "b c001 if a >= 5").unwrap();
+		let out = debugger.execute(&mut core, "c").unwrap();
+		assert!(out.starts_with("Breakpoint 0 at C001"));
+		assert_eq!(core.reg.a, 0x05);
+		// Continuing off the breakpoint runs the loop once more
+		debugger.execute(&mut core, "continue").unwrap();
+		assert_eq!(core.reg.a, 0x06);
+		assert_eq!(core.reg.pc, 0xC001);
+		debugger.execute(&mut core, "d 0").unwrap();
+		assert!(debugger.breakpoints.is_empty());
+	}
+
+	#[test]
+	fn test_step() {
+		use super::*;
+		let mut core = core_with_program(&[0x00, 0x00, 0x00, 0x3C]);
+		let mut debugger = Debugger::create();
+		let out = debugger.execute(&mut core, "s 3").unwrap();
+		assert_eq!(core.reg.pc, 0xC003);
+		assert_eq!(out, "C003: 3C        INC A");
+		assert!(debugger.execute(&mut core, "s x").is_err());
+	}
+
+	#[test]
+	fn test_lock_stops() {
+		use super::*;
+		let mut core = core_with_program(&[0x00, 0xDD]);
+		let mut debugger = Debugger::create();
+		assert_eq!(debugger.run(&mut core, None).unwrap(), StopReason::Locked { opcode: 0xDD, pc: 0xC001 });
+	}
+
+	#[test]
+	fn test_sleep_stops() {
+		use super::*;
+		// DI; HALT
+		let mut core = core_with_program(&[0xF3, 0x76]);
+		let mut debugger = Debugger::create();
+		assert_eq!(debugger.run(&mut core, None).unwrap(), StopReason::Halted);
+		// STOP
+		let mut core = core_with_program(&[0x10, 0x00]);
+		assert_eq!(debugger.run(&mut core, None).unwrap(), StopReason::Stopped);
+	}
+
+	#[test]
+	fn test_interrupt() {
+		use super::*;
+		// JR -2
+		let mut core = core_with_program(&[0x18, 0xFE]);
+		let mut debugger = Debugger::create();
+		debugger.interrupted = Box::new(|| true);
+		let out = debugger.execute(&mut core, "c").unwrap();
+		assert!(out.starts_with("Interrupted"));
+	}
+
+	#[test]
+	fn test_inspect_and_modify() {
+		use super::*;
+		let mut core = core_with_program(&[0x00]);
+		let mut debugger = Debugger::create();
+		debugger.execute(&mut core, "set hl $C123").unwrap();
+		assert_eq!(core.reg.get_hl(), 0xC123);
+		assert!(debugger.execute(&mut core, "set q 1").is_err());
+		debugger.execute(&mut core, "w C010 AB").unwrap();
+		assert_eq!(core.mem.get_mem(0xC010), 0xAB);
+		let out = debugger.execute(&mut core, "x C00F 3").unwrap();
+		assert_eq!(out, "C00F: 00 AB 00");
+		assert!(debugger.execute(&mut core, "r").unwrap().contains("HL:C123"));
+	}
+
+	#[test]
+	fn test_list_around_pc() {
+		use super::*;
+		// LD A,$01; LD BC,$1234; NOP; INC A
+		let mut core = core_with_program(&[0x3E, 0x01, 0x01, 0x34, 0x12, 0x00, 0x3C]);
+		core.reg.pc = 0xC005;
+		let listing = listing_around(&core.mem, core.reg.pc, 4);
+		assert_eq!(listing[0].addr, 0xC002);
+		assert_eq!(listing[1].addr, 0xC005);
+		let mut debugger = Debugger::create();
+		let out = debugger.execute(&mut core, "l 3").unwrap();
+		assert!(out.lines().nth(1).unwrap().starts_with("=> C005"));
+	}
+
+	#[test]
+	fn test_watch_command() {
+		use super::*;
+		// LD HL,$C100; INC (HL); JR -3
+		let mut core = core_with_program(&[0x21, 0x00, 0xC1, 0x34, 0x18, 0xFD]);
+		let mut debugger = Debugger::create();
+		debugger.execute(&mut core, "watch c C100").unwrap();
+		let out = debugger.execute(&mut core, "c").unwrap();
+		assert!(out.starts_with("Watchpoint 0 (Change) at C100 by C003: 00 -> 01"));
+		assert_eq!(core.reg.pc, 0xC004);
+		debugger.execute(&mut core, "c").unwrap();
+		assert_eq!(core.mem.get_mem(0xC100), 0x02);
+		debugger.execute(&mut core, "dw 0").unwrap();
+		assert!(core.mem.watchpoints.is_empty());
+		assert!(debugger.execute(&mut core, "watch x C100").is_err());
+	}
+
+	#[test]
+	fn test_access_watch_command() {
+		use super::*;
+		// LD A,($C100); LD ($C100),A
+		let mut core = core_with_program(&[0xFA, 0x00, 0xC1, 0xEA, 0x00, 0xC1]);
+		let mut debugger = Debugger::create();
+		debugger.execute(&mut core, "watch a C100").unwrap();
+		assert_eq!(debugger.execute(&mut core, "wl").unwrap(), "0: C100-C100 Access");
+		assert!(debugger.execute(&mut core, "c").unwrap().starts_with("Watchpoint 0 (Access) at C100 by C000"));
+		assert!(debugger.execute(&mut core, "c").unwrap().starts_with("Watchpoint 0 (Access) at C100 by C003"));
+	}
+
+	#[test]
+	fn test_symbols() {
+		use super::*;
+		use super::super::symbols::Symbols;
+		// CALL $C005; NOP; NOP; INC A
+		let mut core = core_with_program(&[0xCD, 0x05, 0xC0, 0x00, 0x00, 0x3C]);
+		core.symbols = Symbols::parse("00:c005 Increment\n");
+		let mut debugger = Debugger::create();
+		debugger.execute(&mut core, "b Increment").unwrap();
+		assert_eq!(debugger.breakpoints[0].addr, 0xC005);
+		let listing = debugger.execute(&mut core, "l 2").unwrap();
+		assert!(listing.starts_with("=> C000: CD 05 C0  CALL Increment"));
+		let out = debugger.execute(&mut core, "c").unwrap();
+		assert_eq!(out, "Breakpoint 0 at C005\nC005: 3C        INC A ; Increment");
+	}
+
+	#[test]
+	fn test_banked_breakpoint() {
+		use super::*;
+		use super::super::symbols::Symbols;
+		let mut core = Core::new();
+		let mut data = vec![0; 0x10000];
+		data[0x0147] = 0x11;
+		data[0x0148] = 0x01;
+		core.mem.rom.load_data(data).unwrap();
+		core.symbols = Symbols::parse("02:4000 LoadLevel\n");
+		core.reg.pc = 0x4000;
+		let mut debugger = Debugger::create();
+		assert_eq!(debugger.execute(&mut core, "b LoadLevel").unwrap(), "Breakpoint 0 at 02:4000");
+		assert_eq!(debugger.hit(&core), None);
+		core.mem.set_mem(0x2000, 0x02);
+		assert_eq!(debugger.hit(&core), Some(0));
+	}
+}
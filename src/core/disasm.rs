@@ -0,0 +1,222 @@
+// Turns SM83 machine code into mnemonics, reading through the CPU's view of memory
+
+use std::fmt;
+
+use super::memory::Memory;
+use super::symbols::Symbols;
+
+const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = ["ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP "];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+const ACC: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+	pub addr: u16,
+	pub bytes: Vec<u8>,
+	pub text: String,
+	pub target: Option<u16>, // Where a jump, call or RST goes, with relative offsets already applied
+}
+
+impl Instruction {
+	pub fn len(&self) -> u16 {
+		self.bytes.len() as u16
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.bytes.is_empty()
+	}
+
+	// Swaps 16-bit operands like $2A3C for their labels, resolved against the banks mapped in mem
+	pub fn symbolize(&mut self, mem:&Memory, symbols:&Symbols) {
+		let mut text = String::new();
+		let mut rest = self.text.as_str();
+		while let Some(at) = rest.find('$') {
+			text.push_str(&rest[.. at]);
+			let operand = &rest[at + 1 ..];
+			let digits = operand.chars().take_while(|c| c.is_ascii_hexdigit()).count();
+			let label = if digits == 4 {
+				u16::from_str_radix(&operand[.. 4], 16).ok().and_then(|addr| symbols.lookup(mem, addr))
+			} else {
+				None
+			};
+			match label {
+				Some(label) => text.push_str(label),
+				None => text.push_str(&rest[at .. at + 1 + digits]),
+			}
+			rest = &operand[digits ..];
+		}
+		text.push_str(rest);
+		self.text = text;
+	}
+}
+
+impl fmt::Display for Instruction {
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+		write!(f, "{:04X}: {:<9} {}", self.addr, bytes.join(" "), self.text)
+	}
+}
+
+// Decodes the single instruction at addr
+pub fn decode(mem:&Memory, addr:u16) -> Instruction {
+	let op = mem.get_mem(addr);
+	let n = mem.get_mem(addr.wrapping_add(1));
+	let nn = (n as u16) | ((mem.get_mem(addr.wrapping_add(2)) as u16) << 8);
+	// JR offsets count from the end of the two byte instruction
+	let rel = addr.wrapping_add(2).wrapping_add(n as i8 as u16);
+	let (x, y, z) = (op >> 6, ((op >> 3) & 0x07) as usize, (op & 0x07) as usize);
+	let (p, q) = (y >> 1, y & 0x01);
+
+	let (len, text, target): (u16, String, Option<u16>) = match (x, z) {
+		(0, 0) => match y {
+			0 => (1, "NOP".to_string(), None),
+			1 => (3, format!("LD (${:04X}),SP", nn), None),
+			2 => (2, "STOP".to_string(), None),
+			3 => (2, format!("JR ${:04X}", rel), Some(rel)),
+			_ => (2, format!("JR {},${:04X}", CC[y - 4], rel), Some(rel)),
+		},
+		(0, 1) if q == 0 => (3, format!("LD {},${:04X}", RP[p], nn), None),
+		(0, 1) => (1, format!("ADD HL,{}", RP[p]), None),
+		(0, 2) => {
+			let mem_op = ["(BC)", "(DE)", "(HL+)", "(HL-)"][p];
+			if q == 0 {
+				(1, format!("LD {},A", mem_op), None)
+			} else {
+				(1, format!("LD A,{}", mem_op), None)
+			}
+		}
+		(0, 3) if q == 0 => (1, format!("INC {}", RP[p]), None),
+		(0, 3) => (1, format!("DEC {}", RP[p]), None),
+		(0, 4) => (1, format!("INC {}", R[y]), None),
+		(0, 5) => (1, format!("DEC {}", R[y]), None),
+		(0, 6) => (2, format!("LD {},${:02X}", R[y], n), None),
+		(0, _) => (1, ACC[y].to_string(), None),
+		(1, _) if y == 6 && z == 6 => (1, "HALT".to_string(), None),
+		(1, _) => (1, format!("LD {},{}", R[y], R[z]), None),
+		(2, _) => (1, format!("{}{}", ALU[y], R[z]), None),
+		(_, 0) => match y {
+			0 ..= 3 => (1, format!("RET {}", CC[y]), None),
+			4 => (2, format!("LDH (${:04X}),A", 0xFF00 | n as u16), None),
+			5 => (2, format!("ADD SP,{}", n as i8), None),
+			6 => (2, format!("LDH A,(${:04X})", 0xFF00 | n as u16), None),
+			_ => (2, format!("LD HL,SP{:+}", n as i8), None),
+		},
+		(_, 1) if q == 0 => (1, format!("POP {}", RP2[p]), None),
+		(_, 1) => (1, ["RET", "RETI", "JP HL", "LD SP,HL"][p].to_string(), None),
+		(_, 2) => match y {
+			0 ..= 3 => (3, format!("JP {},${:04X}", CC[y], nn), Some(nn)),
+			4 => (1, "LD ($FF00+C),A".to_string(), None),
+			5 => (3, format!("LD (${:04X}),A", nn), None),
+			6 => (1, "LD A,($FF00+C)".to_string(), None),
+			_ => (3, format!("LD A,(${:04X})", nn), None),
+		},
+		(_, 3) => match y {
+			0 => (3, format!("JP ${:04X}", nn), Some(nn)),
+			1 => {
+				let (cx, cy, cz) = (n >> 6, ((n >> 3) & 0x07) as usize, (n & 0x07) as usize);
+				let text = match cx {
+					0 => format!("{} {}", ROT[cy], R[cz]),
+					1 => format!("BIT {},{}", cy, R[cz]),
+					2 => format!("RES {},{}", cy, R[cz]),
+					_ => format!("SET {},{}", cy, R[cz]),
+				};
+				(2, text, None)
+			}
+			6 => (1, "DI".to_string(), None),
+			7 => (1, "EI".to_string(), None),
+			_ => (1, format!("DB ${:02X}", op), None),
+		},
+		(_, 4) if y < 4 => (3, format!("CALL {},${:04X}", CC[y], nn), Some(nn)),
+		(_, 5) if q == 0 => (1, format!("PUSH {}", RP2[p]), None),
+		(_, 5) if p == 0 => (3, format!("CALL ${:04X}", nn), Some(nn)),
+		(_, 6) => (2, format!("{}${:02X}", ALU[y], n), None),
+		(_, 7) => (1, format!("RST ${:02X}", y * 8), Some(y as u16 * 8)),
+		// The eleven opcodes that lock up the CPU
+		_ => (1, format!("DB ${:02X}", op), None),
+	};
+
+	Instruction {
+		addr,
+		bytes: (0 .. len).map(|i| mem.get_mem(addr.wrapping_add(i))).collect(),
+		text,
+		target
+	}
+}
+
+// Linear sweep over start to end inclusive, so data between code decodes as instructions too
+pub fn disassemble(mem:&Memory, start:u16, end:u16) -> Vec<Instruction> {
+	let mut listing = Vec::new();
+	let mut addr = start as u32;
+	while addr <= end as u32 {
+		let ins = decode(mem, addr as u16);
+		addr += ins.len() as u32;
+		listing.push(ins);
+	}
+	listing
+}
+
+mod test {
+	#[cfg(test)]
+	fn mem_with(program:&[u8]) -> super::Memory {
+		let mut mem = super::Memory::create_memory();
+		for (i, val) in program.iter().enumerate() {
+			mem.set_mem(0xC000 + i as u16, *val);
+		}
+		mem
+	}
+
+	#[test]
+	fn test_decode() {
+		use super::*;
+		let mem = mem_with(&[0x31, 0xFE, 0xFF, 0x7E, 0xE0, 0x40, 0xF8, 0xFE, 0xCB, 0x7C, 0xCB, 0x36, 0xD3]);
+		let listing = disassemble(&mem, 0xC000, 0xC00C);
+		let text: Vec<&str> = listing.iter().map(|ins| ins.text.as_str()).collect();
+		assert_eq!(text, vec!["LD SP,$FFFE", "LD A,(HL)", "LDH ($FF40),A", "LD HL,SP-2", "BIT 7,H", "SWAP (HL)", "DB $D3"]);
+		assert_eq!(listing[0].bytes, vec![0x31, 0xFE, 0xFF]);
+		assert_eq!(format!("{}", listing[0]), "C000: 31 FE FF  LD SP,$FFFE");
+	}
+
+	#[test]
+	fn test_jump_targets() {
+		use super::*;
+		// JR NZ,-2; CALL $1234; RST $38; JR +5
+		let mem = mem_with(&[0x20, 0xFE, 0xCD, 0x34, 0x12, 0xFF, 0x18, 0x05]);
+		let listing = disassemble(&mem, 0xC000, 0xC007);
+		assert_eq!(listing[0].text, "JR NZ,$C000");
+		assert_eq!(listing[0].target, Some(0xC000));
+		assert_eq!(listing[1].text, "CALL $1234");
+		assert_eq!(listing[1].target, Some(0x1234));
+		assert_eq!(listing[2].target, Some(0x0038));
+		assert_eq!(listing[3].target, Some(0xC00D));
+	}
+
+	#[test]
+	fn test_every_opcode_decodes() {
+		use super::*;
+		for op in 0 ..= 0xFFu8 {
+			let mut mem = mem_with(&[op, 0x00, 0x00]);
+			let ins = decode(&mem, 0xC000);
+			assert!(!ins.text.is_empty());
+			mem.set_mem(0xC000, 0xCB);
+			mem.set_mem(0xC001, op);
+			assert_eq!(decode(&mem, 0xC000).len(), 2);
+		}
+	}
+
+	#[test]
+	fn test_symbolize() {
+		use super::*;
+		// CALL $2A3C; LD A,($C100); LD BC,$1234; LDH ($FF40),A
+		let mem = mem_with(&[0xCD, 0x3C, 0x2A, 0xFA, 0x00, 0xC1, 0x01, 0x34, 0x12, 0xE0, 0x40]);
+		let symbols = Symbols::parse("00:2a3c UpdateSprites\n00:c100 wScore\n");
+		let text: Vec<String> = disassemble(&mem, 0xC000, 0xC00A).into_iter().map(|mut ins| {
+			ins.symbolize(&mem, &symbols);
+			ins.text
+		}).collect();
+		assert_eq!(text, vec!["CALL UpdateSprites", "LD A,(wScore)", "LD BC,$1234", "LDH ($FF40),A"]);
+	}
+}
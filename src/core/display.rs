@@ -1,39 +1,684 @@
+use super::interrupts::{Interrupts, Interrupt};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+pub const DOTS_PER_LINE: u64 = 456;
+pub const LINES_PER_FRAME: u8 = 154;
+const OAM_SCAN_DOTS: u64 = 80;
+const TRANSFER_DOTS: u64 = 172;
+
+pub const MODE_HBLANK: u8 = 0;
+pub const MODE_VBLANK: u8 = 1;
+pub const MODE_OAM_SCAN: u8 = 2;
+pub const MODE_TRANSFER: u8 = 3;
+
 pub struct Display {
+	pub vram: [u8; 0x2000], // 0x8000-0x9FFF
+	pub oam: [u8; 0xA0], // 0xFE00-0xFE9F
 	pub ly_coord: u8,
-	pub steps: u64
+	pub steps: u64, // Dots into the current line
+	pub mode: u8,
+	pub lcdc: u8,
+	pub stat: u8, // Only the interrupt select bits, mode and coincidence are derived
+	pub scy: u8,
+	pub scx: u8,
+	pub lyc: u8,
+	pub bgp: u8,
+	pub obp0: u8,
+	pub obp1: u8,
+	pub wy: u8,
+	pub wx: u8,
+	pub stat_line: bool, // STAT interrupts fire on the rising edge of this
+	pub window_line: u8, // Only advances on lines where the window was drawn
+	pub line_colors: [u8; SCREEN_WIDTH], // Raw BG/window colour indices for the current line
+	pub back_buffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+	pub framebuffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT], // Last complete frame
+	pub frames: u64,
+	pub stub_ly: bool, // LY always reads 0x90, matching the usual reference trace setup
 }
 
 impl Display {
 	pub fn create() -> Display {
 		Display {
+			vram: [0; 0x2000],
+			oam: [0; 0xA0],
 			ly_coord: 0,
-			steps: 0
+			steps: 0,
+			mode: MODE_OAM_SCAN,
+			lcdc: 0x91,
+			stat: 0x00,
+			scy: 0,
+			scx: 0,
+			lyc: 0,
+			bgp: 0xFC,
+			obp0: 0xFF,
+			obp1: 0xFF,
+			wy: 0,
+			wx: 0,
+			stat_line: false,
+			window_line: 0,
+			line_colors: [0; SCREEN_WIDTH],
+			back_buffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+			framebuffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+			frames: 0,
+			stub_ly: false
+		}
+	}
+
+	pub fn lcd_on(&self) -> bool {
+		self.lcdc & 0x80 == 0x80
+	}
+
+	// The PPU owns VRAM during pixel transfer and OAM during both scan and transfer
+	fn vram_locked(&self) -> bool {
+		self.lcd_on() && self.mode == MODE_TRANSFER
+	}
+
+	fn oam_locked(&self) -> bool {
+		self.lcd_on() && (self.mode == MODE_OAM_SCAN || self.mode == MODE_TRANSFER)
+	}
+
+	pub fn update(&mut self, steps:u64, int:&mut Interrupts) {
+		if !self.lcd_on() {
+			return;
+		}
+		for _ in 0 .. steps {
+			self.dot(int);
 		}
 	}
 
-	pub fn update(&mut self, steps:u64) {
-		self.steps += steps;
-		if self.steps > 456 {
-			self.steps -= 456;
+	fn dot(&mut self, int:&mut Interrupts) {
+		self.steps += 1;
+		if self.steps == DOTS_PER_LINE {
+			self.steps = 0;
 			self.ly_coord += 1;
-			if self.ly_coord == 157 {
+			if self.ly_coord == LINES_PER_FRAME {
 				self.ly_coord = 0;
 			}
 		}
+
+		let mode = if self.ly_coord >= 144 {
+			MODE_VBLANK
+		} else if self.steps < OAM_SCAN_DOTS {
+			MODE_OAM_SCAN
+		} else if self.steps < OAM_SCAN_DOTS + TRANSFER_DOTS {
+			MODE_TRANSFER
+		} else {
+			MODE_HBLANK
+		};
+		if mode != self.mode {
+			self.mode = mode;
+			match mode {
+				MODE_HBLANK => self.render_line(),
+				MODE_VBLANK => {
+					self.framebuffer = self.back_buffer;
+					self.frames += 1;
+					self.window_line = 0;
+					int.request(Interrupt::VBlank);
+				}
+				_ => {}
+			}
+		}
+		self.update_stat_line(int);
+	}
+
+	fn update_stat_line(&mut self, int:&mut Interrupts) {
+		let line = (self.stat & 0x40 != 0 && self.ly_coord == self.lyc)
+			|| (self.stat & 0x20 != 0 && self.mode == MODE_OAM_SCAN)
+			|| (self.stat & 0x10 != 0 && self.mode == MODE_VBLANK)
+			|| (self.stat & 0x08 != 0 && self.mode == MODE_HBLANK);
+		if line && !self.stat_line {
+			int.request(Interrupt::Stat);
+		}
+		self.stat_line = line;
+	}
+
+	// Maps a 2-bit colour index through one of the palette registers
+	fn shade(palette:u8, color:u8) -> u8 {
+		(palette >> (color * 2)) & 0x03
+	}
+
+	// Colour index of pixel (x, y) within the tile data starting at addr
+	fn tile_data_pixel(&self, addr:usize, x:u8, y:u8) -> u8 {
+		let row = addr + (y as usize) * 2;
+		let bit = 7 - x;
+		let lo = (self.vram[row] >> bit) & 0x01;
+		let hi = (self.vram[row + 1] >> bit) & 0x01;
+		(hi << 1) | lo
+	}
+
+	// Colour index of pixel (x, y) within the BG/window tile numbered tile_num
+	fn tile_pixel(&self, tile_num:u8, x:u8, y:u8) -> u8 {
+		let addr = if self.lcdc & 0x10 != 0 {
+			(tile_num as usize) * 16
+		} else {
+			(0x1000 + (tile_num as i8 as isize) * 16) as usize
+		};
+		self.tile_data_pixel(addr, x, y)
+	}
+
+	// Colour index at (x, y) of the 256x256 map selected by the given LCDC bit
+	fn map_pixel(&self, map_bit:u8, x:u8, y:u8) -> u8 {
+		let map_base = if self.lcdc & map_bit != 0 { 0x1C00 } else { 0x1800 };
+		let tile_index = map_base + (y as usize / 8) * 32 + (x as usize / 8);
+		self.tile_pixel(self.vram[tile_index], x % 8, y % 8)
+	}
+
+	fn render_line(&mut self) {
+		let ly = self.ly_coord;
+		let bg_enabled = self.lcdc & 0x01 != 0;
+		let window_visible = bg_enabled && self.lcdc & 0x20 != 0 && ly >= self.wy && self.wx <= 166;
+		let mut window_drawn = false;
+		for x in 0 .. SCREEN_WIDTH {
+			let color = if !bg_enabled {
+				0
+			} else if window_visible && x + 7 >= self.wx as usize {
+				window_drawn = true;
+				let wx = (x + 7 - self.wx as usize) as u8;
+				self.map_pixel(0x40, wx, self.window_line)
+			} else {
+				let bx = self.scx.wrapping_add(x as u8);
+				let by = self.scy.wrapping_add(ly);
+				self.map_pixel(0x08, bx, by)
+			};
+			self.line_colors[x] = color;
+			self.back_buffer[(ly as usize) * SCREEN_WIDTH + x] = Display::shade(self.bgp, color);
+		}
+		if window_drawn {
+			self.window_line += 1;
+		}
+		if self.lcdc & 0x02 != 0 {
+			self.render_sprites();
+		}
+	}
+
+	// OAM indices of the first ten sprites on the current line, in DMG drawing priority order
+	fn line_sprites(&self) -> Vec<usize> {
+		let height = if self.lcdc & 0x04 != 0 { 16 } else { 8 };
+		let ly = self.ly_coord as i16;
+		let mut sprites: Vec<usize> = (0 .. 40).filter(|i| {
+			let top = self.oam[i * 4] as i16 - 16;
+			ly >= top && ly < top + height
+		}).take(10).collect();
+		// Smaller X wins, ties go to the earlier OAM entry (the sort is stable)
+		sprites.sort_by_key(|i| self.oam[i * 4 + 1]);
+		sprites
+	}
+
+	fn render_sprites(&mut self) {
+		let tall = self.lcdc & 0x04 != 0;
+		let ly = self.ly_coord as i16;
+		let sprites = self.line_sprites();
+		for x in 0 .. SCREEN_WIDTH {
+			for i in &sprites {
+				let entry = &self.oam[i * 4 .. i * 4 + 4];
+				let left = entry[1] as i16 - 8;
+				let column = x as i16 - left;
+				if !(0 .. 8).contains(&column) {
+					continue;
+				}
+				let attrs = entry[3];
+				let mut row = ly - (entry[0] as i16 - 16);
+				if attrs & 0x40 != 0 {
+					row = if tall { 15 } else { 7 } - row;
+				}
+				let column = if attrs & 0x20 != 0 { 7 - column } else { column };
+				let tile = if tall { entry[2] & 0xFE } else { entry[2] } as usize + (row as usize / 8);
+				let color = self.tile_data_pixel(tile * 16, column as u8, (row % 8) as u8);
+				if color == 0 {
+					// Transparent, so a lower priority sprite may still show here
+					continue;
+				}
+				if attrs & 0x80 == 0 || self.line_colors[x] == 0 {
+					let palette = if attrs & 0x10 != 0 { self.obp1 } else { self.obp0 };
+					self.back_buffer[(ly as usize) * SCREEN_WIDTH + x] = Display::shade(palette, color);
+				}
+				break;
+			}
+		}
+	}
+
+	fn get_stat(&self) -> u8 {
+		let coincidence = (self.ly_coord == self.lyc) as u8;
+		0x80 | self.stat | (coincidence << 2) | self.mode
+	}
+
+	fn set_lcdc(&mut self, val:u8) {
+		let was_on = self.lcd_on();
+		self.lcdc = val;
+		if was_on && !self.lcd_on() {
+			// LY is held at 0 and STAT reports HBlank while off
+			self.ly_coord = 0;
+			self.steps = 0;
+			self.mode = MODE_HBLANK;
+			self.stat_line = false;
+			self.window_line = 0;
+		} else if !was_on && self.lcd_on() {
+			self.mode = MODE_OAM_SCAN;
+		}
 	}
 
 	pub fn get_mem(&self, loc:u16) -> u8 {
 		match loc {
-			0xFF40 => 0,
+			0x8000 ..= 0x9FFF => {
+				if self.vram_locked() { 0xFF } else { self.vram[(loc - 0x8000) as usize] }
+			}
+			0xFE00 ..= 0xFE9F => {
+				if self.oam_locked() { 0xFF } else { self.oam[(loc - 0xFE00) as usize] }
+			}
+			0xFF40 => self.lcdc,
+			0xFF41 => self.get_stat(),
+			0xFF42 => self.scy,
+			0xFF43 => self.scx,
+			0xFF44 if self.stub_ly => 0x90,
 			0xFF44 => self.ly_coord,
+			0xFF45 => self.lyc,
+			0xFF47 => self.bgp,
+			0xFF48 => self.obp0,
+			0xFF49 => self.obp1,
+			0xFF4A => self.wy,
+			0xFF4B => self.wx,
 			_ => {
-				println!("Disp read from {:2X} unsupported", loc);
+				log!(Ppu, Warn, "Read from unsupported display register {:04X}", loc);
 				0
 			}
 		}
 	}
 
-	pub fn set_mem(&mut self, loc:u16, val:u8) {
+	pub fn set_mem(&mut self, loc:u16, val:u8, int:&mut Interrupts) {
+		match loc {
+			0x8000 ..= 0x9FFF if !self.vram_locked() => self.vram[(loc - 0x8000) as usize] = val,
+			0xFE00 ..= 0xFE9F if !self.oam_locked() => self.oam[(loc - 0xFE00) as usize] = val,
+			0xFF40 => self.set_lcdc(val),
+			0xFF41 => {
+				self.stat = val & 0x78;
+				if self.lcd_on() {
+					self.update_stat_line(int);
+				}
+			}
+			0xFF42 => self.scy = val,
+			0xFF43 => self.scx = val,
+			0xFF44 => {}, // LY is read only
+			0xFF45 => {
+				self.lyc = val;
+				if self.lcd_on() {
+					self.update_stat_line(int);
+				}
+			}
+			0xFF47 => self.bgp = val,
+			0xFF48 => self.obp0 = val,
+			0xFF49 => self.obp1 = val,
+			0xFF4A => self.wy = val,
+			0xFF4B => self.wx = val,
+			_ => {} // Locked out or not a display register
+		}
+	}
+}
+
+mod test {
+	#[test]
+	fn test_mode_timing() {
+		use super::*;
+		let mut disp = Display::create();
+		let mut int = Interrupts::create();
+		assert_eq!(disp.get_mem(0xFF41) & 0x03, MODE_OAM_SCAN);
+		disp.update(80, &mut int);
+		assert_eq!(disp.get_mem(0xFF41) & 0x03, MODE_TRANSFER);
+		disp.update(172, &mut int);
+		assert_eq!(disp.get_mem(0xFF41) & 0x03, MODE_HBLANK);
+		disp.update(204, &mut int);
+		assert_eq!(disp.get_mem(0xFF44), 1);
+		assert_eq!(disp.get_mem(0xFF41) & 0x03, MODE_OAM_SCAN);
+	}
+
+	#[test]
+	fn test_frame_wrap() {
+		use super::*;
+		let mut disp = Display::create();
+		let mut int = Interrupts::create();
+		int.iflag = 0;
+		disp.update(DOTS_PER_LINE * 144 - 1, &mut int);
+		assert_eq!(disp.get_mem(0xFF44), 143);
+		assert_eq!(int.iflag, 0);
+		disp.update(1, &mut int);
+		assert_eq!(disp.get_mem(0xFF44), 144);
+		assert_eq!(disp.get_mem(0xFF41) & 0x03, MODE_VBLANK);
+		assert_eq!(int.iflag, Interrupt::VBlank.bit());
+		disp.update(DOTS_PER_LINE * 9, &mut int);
+		assert_eq!(disp.get_mem(0xFF44), 153);
+		disp.update(DOTS_PER_LINE, &mut int);
+		assert_eq!(disp.get_mem(0xFF44), 0);
+	}
+
+	#[test]
+	fn test_lyc() {
+		use super::*;
+		let mut disp = Display::create();
+		let mut int = Interrupts::create();
+		int.iflag = 0;
+		disp.set_mem(0xFF45, 2, &mut int);
+		disp.set_mem(0xFF41, 0x40, &mut int);
+		disp.update(DOTS_PER_LINE, &mut int);
+		assert_eq!(disp.get_mem(0xFF41) & 0x04, 0);
+		assert_eq!(int.iflag, 0);
+		disp.update(DOTS_PER_LINE, &mut int);
+		assert_eq!(disp.get_mem(0xFF41) & 0x04, 0x04);
+		assert_eq!(int.iflag, Interrupt::Stat.bit());
+	}
+
+	#[test]
+	fn test_stat_mode_interrupts() {
+		use super::*;
+		let mut disp = Display::create();
+		let mut int = Interrupts::create();
+		int.iflag = 0;
+		disp.set_mem(0xFF41, 0x08, &mut int);
+		disp.update(80 + 172 - 1, &mut int);
+		assert_eq!(int.iflag, 0);
+		disp.update(1, &mut int);
+		assert_eq!(int.iflag, Interrupt::Stat.bit());
+		// Still high through HBlank so no second request
+		int.iflag = 0;
+		disp.update(100, &mut int);
+		assert_eq!(int.iflag, 0);
+	}
+
+	#[test]
+	fn test_lcd_off() {
+		use super::*;
+		let mut disp = Display::create();
+		let mut int = Interrupts::create();
+		disp.update(DOTS_PER_LINE * 10 + 100, &mut int);
+		disp.set_mem(0xFF40, 0x11, &mut int);
+		assert_eq!(disp.get_mem(0xFF44), 0);
+		assert_eq!(disp.get_mem(0xFF41) & 0x03, MODE_HBLANK);
+		disp.update(DOTS_PER_LINE * 10, &mut int);
+		assert_eq!(disp.get_mem(0xFF44), 0);
+		disp.set_mem(0xFF40, 0x91, &mut int);
+		assert_eq!(disp.get_mem(0xFF41) & 0x03, MODE_OAM_SCAN);
+	}
+
+	#[test]
+	fn test_vram_lockout() {
+		use super::*;
+		let mut disp = Display::create();
+		let mut int = Interrupts::create();
+		disp.set_mem(0x8010, 0x3C, &mut int);
+		assert_eq!(disp.get_mem(0x8010), 0x3C);
+		disp.update(80, &mut int);
+		assert_eq!(disp.get_mem(0x8010), 0xFF);
+		disp.set_mem(0x8010, 0x11, &mut int);
+		disp.update(172, &mut int);
+		assert_eq!(disp.get_mem(0x8010), 0x3C);
+	}
+
+	#[test]
+	fn test_oam_lockout() {
+		use super::*;
+		let mut disp = Display::create();
+		let mut int = Interrupts::create();
+		disp.set_mem(0xFE00, 0x12, &mut int);
+		assert_eq!(disp.oam[0], 0x00);
+		assert_eq!(disp.get_mem(0xFE00), 0xFF);
+		disp.update(80 + 172, &mut int);
+		disp.set_mem(0xFE00, 0x12, &mut int);
+		assert_eq!(disp.get_mem(0xFE00), 0x12);
+	}
+
+	#[test]
+	fn test_lcd_off_access() {
+		use super::*;
+		let mut disp = Display::create();
+		let mut int = Interrupts::create();
+		disp.update(100, &mut int);
+		disp.set_mem(0xFF40, 0x00, &mut int);
+		disp.set_mem(0x9FFF, 0x77, &mut int);
+		disp.set_mem(0xFE9F, 0x66, &mut int);
+		assert_eq!(disp.get_mem(0x9FFF), 0x77);
+		assert_eq!(disp.get_mem(0xFE9F), 0x66);
+	}
+
+	// Writes an 8x8 tile where every pixel has the given colour index
+	#[cfg(test)]
+	fn fill_tile(disp:&mut super::Display, addr:usize, color:u8) {
+		for row in 0 .. 8 {
+			disp.vram[addr + row * 2] = if color & 0x1 != 0 { 0xFF } else { 0x00 };
+			disp.vram[addr + row * 2 + 1] = if color & 0x2 != 0 { 0xFF } else { 0x00 };
+		}
+	}
+
+	#[test]
+	fn test_tile_pixel() {
+		use super::*;
+		let mut disp = Display::create();
+		disp.vram[0x10] = 0b1000_0001;
+		disp.vram[0x11] = 0b1000_0000;
+		assert_eq!(disp.tile_pixel(1, 0, 0), 3);
+		assert_eq!(disp.tile_pixel(1, 7, 0), 1);
+		assert_eq!(disp.tile_pixel(1, 3, 0), 0);
+		// Signed addressing puts tile 0 at 0x9000 and tile 0xFF at 0x8FF0
+		disp.lcdc &= !0x10;
+		disp.vram[0x1000] = 0x80;
+		disp.vram[0x0FF1] = 0x80;
+		assert_eq!(disp.tile_pixel(0, 0, 0), 1);
+		assert_eq!(disp.tile_pixel(0xFF, 0, 0), 2);
+	}
+
+	#[test]
+	fn test_background_scroll() {
+		use super::*;
+		let mut disp = Display::create();
+		let mut int = Interrupts::create();
+		fill_tile(&mut disp, 0x10, 3);
+		disp.vram[0x1800 + 1] = 1; // Map entry (1, 0)
+		disp.set_mem(0xFF47, 0xE4, &mut int);
+		disp.update(DOTS_PER_LINE * 154, &mut int);
+		assert_eq!(disp.frames, 1);
+		assert_eq!(disp.framebuffer[7], 0);
+		assert_eq!(disp.framebuffer[8], 3);
+		assert_eq!(disp.framebuffer[15], 3);
+		assert_eq!(disp.framebuffer[16], 0);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 7 + 8], 3);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 8 + 8], 0);
+
+		disp.set_mem(0xFF43, 4, &mut int);
+		disp.set_mem(0xFF42, 4, &mut int);
+		disp.update(DOTS_PER_LINE * 154, &mut int);
+		assert_eq!(disp.framebuffer[3], 0);
+		assert_eq!(disp.framebuffer[4], 3);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 3 + 4], 3);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 4 + 4], 0);
+	}
 
+	#[test]
+	fn test_bgp_mapping() {
+		use super::*;
+		let mut disp = Display::create();
+		let mut int = Interrupts::create();
+		fill_tile(&mut disp, 0x00, 1);
+		disp.set_mem(0xFF47, 0b0000_1100, &mut int);
+		disp.update(DOTS_PER_LINE * 154, &mut int);
+		assert_eq!(disp.framebuffer[0], 3);
+		disp.set_mem(0xFF40, 0x90, &mut int); // BG off shows colour 0
+		disp.update(DOTS_PER_LINE * 154, &mut int);
+		assert_eq!(disp.framebuffer[0], 0);
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn test_window() {
+		use super::*;
+		let mut disp = Display::create();
+		let mut int = Interrupts::create();
+		fill_tile(&mut disp, 0x10, 2);
+		for i in 0 .. 0x400 {
+			disp.vram[0x1C00 + i] = 1; // Whole window map uses tile 1
+		}
+		disp.set_mem(0xFF47, 0xE4, &mut int);
+		disp.set_mem(0xFF4A, 10, &mut int);
+		disp.set_mem(0xFF4B, 27, &mut int);
+		disp.set_mem(0xFF40, 0xF1, &mut int);
+		disp.update(DOTS_PER_LINE * 154, &mut int);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 9 + 20], 0);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 10 + 19], 0);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 10 + 20], 2);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 143 + 159], 2);
+	}
+
+	#[test]
+	fn test_window_line_counter() {
+		use super::*;
+		let mut disp = Display::create();
+		let mut int = Interrupts::create();
+		fill_tile(&mut disp, 0x10, 3);
+		disp.vram[0x1C00 + 32] = 1; // Second row of the window map
+		disp.set_mem(0xFF47, 0xE4, &mut int);
+		disp.set_mem(0xFF4B, 7, &mut int);
+		disp.set_mem(0xFF40, 0xF1, &mut int);
+		// Hide the window for lines 8-15, so window row 8 is drawn on line 16
+		disp.update(DOTS_PER_LINE * 8, &mut int);
+		disp.set_mem(0xFF4B, 200, &mut int);
+		disp.update(DOTS_PER_LINE * 8, &mut int);
+		disp.set_mem(0xFF4B, 7, &mut int);
+		disp.update(DOTS_PER_LINE * 138, &mut int);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 8], 0);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 16], 3);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 24], 0);
+	}
+
+	// Places sprite number index at screen coordinates (x, y)
+	#[cfg(test)]
+	fn place_sprite(disp:&mut super::Display, index:usize, x:u8, y:u8, tile:u8, attrs:u8) {
+		disp.oam[index * 4] = y + 16;
+		disp.oam[index * 4 + 1] = x + 8;
+		disp.oam[index * 4 + 2] = tile;
+		disp.oam[index * 4 + 3] = attrs;
+	}
+
+	#[cfg(test)]
+	fn sprite_display() -> (super::Display, super::Interrupts) {
+		use super::*;
+		let mut disp = Display::create();
+		let mut int = Interrupts::create();
+		disp.set_mem(0xFF47, 0xE4, &mut int);
+		disp.set_mem(0xFF48, 0xE4, &mut int);
+		disp.set_mem(0xFF49, 0x1B, &mut int);
+		disp.set_mem(0xFF40, 0x93, &mut int);
+		(disp, int)
+	}
+
+	#[test]
+	fn test_sprite_basic() {
+		use super::*;
+		let (mut disp, mut int) = sprite_display();
+		fill_tile(&mut disp, 0x10, 1);
+		place_sprite(&mut disp, 0, 20, 30, 1, 0x00);
+		place_sprite(&mut disp, 1, 60, 30, 1, 0x10);
+		disp.update(DOTS_PER_LINE * 154, &mut int);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 30 + 19], 0);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 30 + 20], 1);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 37 + 27], 1);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 38 + 27], 0);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 30 + 60], 2);
+		// Disabling OBJ hides them
+		disp.set_mem(0xFF40, 0x91, &mut int);
+		disp.update(DOTS_PER_LINE * 154, &mut int);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 30 + 20], 0);
+	}
+
+	#[test]
+	fn test_sprite_flip() {
+		use super::*;
+		let (mut disp, mut int) = sprite_display();
+		// Tile 1 has only its top-left pixel set
+		disp.vram[0x10] = 0x80;
+		disp.vram[0x11] = 0x80;
+		place_sprite(&mut disp, 0, 0, 0, 1, 0x00);
+		place_sprite(&mut disp, 1, 16, 0, 1, 0x20);
+		place_sprite(&mut disp, 2, 32, 0, 1, 0x40);
+		place_sprite(&mut disp, 3, 48, 0, 1, 0x60);
+		disp.update(DOTS_PER_LINE * 154, &mut int);
+		assert_eq!(disp.framebuffer[0], 3);
+		assert_eq!(disp.framebuffer[16 + 7], 3);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 7 + 32], 3);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 7 + 48 + 7], 3);
+		assert_eq!(disp.framebuffer[16], 0);
+		assert_eq!(disp.framebuffer[32], 0);
+	}
+
+	#[test]
+	fn test_sprite_tall() {
+		use super::*;
+		let (mut disp, mut int) = sprite_display();
+		fill_tile(&mut disp, 0x20, 1);
+		fill_tile(&mut disp, 0x30, 2);
+		disp.set_mem(0xFF40, 0x97, &mut int);
+		// Bit 0 of the tile number is ignored in 8x16 mode
+		place_sprite(&mut disp, 0, 0, 0, 3, 0x00);
+		place_sprite(&mut disp, 1, 16, 0, 2, 0x40);
+		disp.update(DOTS_PER_LINE * 154, &mut int);
+		assert_eq!(disp.framebuffer[0], 1);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 15], 2);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 16], 0);
+		assert_eq!(disp.framebuffer[16], 2);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 15 + 16], 1);
+	}
+
+	#[test]
+	fn test_sprite_line_limit() {
+		use super::*;
+		let (mut disp, mut int) = sprite_display();
+		fill_tile(&mut disp, 0x10, 3);
+		for i in 0 .. 12 {
+			place_sprite(&mut disp, i, (i * 10) as u8, 50, 1, 0x00);
+		}
+		disp.update(DOTS_PER_LINE * 154, &mut int);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 50 + 90], 3);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 50 + 100], 0);
+		assert_eq!(disp.framebuffer[SCREEN_WIDTH * 50 + 110], 0);
+	}
+
+	#[test]
+	fn test_sprite_priority() {
+		use super::*;
+		let (mut disp, mut int) = sprite_display();
+		fill_tile(&mut disp, 0x10, 1);
+		fill_tile(&mut disp, 0x20, 2);
+		// Lower X wins even though it comes later in OAM
+		place_sprite(&mut disp, 0, 14, 0, 1, 0x00);
+		place_sprite(&mut disp, 1, 10, 0, 2, 0x00);
+		// On equal X the earlier OAM entry wins
+		place_sprite(&mut disp, 2, 40, 0, 1, 0x00);
+		place_sprite(&mut disp, 3, 40, 0, 2, 0x00);
+		disp.update(DOTS_PER_LINE * 154, &mut int);
+		assert_eq!(disp.framebuffer[14], 2);
+		assert_eq!(disp.framebuffer[18], 1);
+		assert_eq!(disp.framebuffer[40], 1);
+	}
+
+	#[test]
+	fn test_sprite_transparency() {
+		use super::*;
+		let (mut disp, mut int) = sprite_display();
+		fill_tile(&mut disp, 0x20, 2);
+		// Tile 1 is all colour 0 so the sprite behind it shows through
+		place_sprite(&mut disp, 0, 0, 0, 1, 0x00);
+		place_sprite(&mut disp, 1, 0, 0, 2, 0x00);
+		disp.update(DOTS_PER_LINE * 154, &mut int);
+		assert_eq!(disp.framebuffer[0], 2);
+	}
+
+	#[test]
+	fn test_sprite_bg_priority() {
+		use super::*;
+		let (mut disp, mut int) = sprite_display();
+		fill_tile(&mut disp, 0x10, 3);
+		fill_tile(&mut disp, 0x20, 1);
+		disp.vram[0x1800] = 2; // BG tile (0, 0) is colour 1
+		place_sprite(&mut disp, 0, 4, 0, 1, 0x80);
+		disp.update(DOTS_PER_LINE * 154, &mut int);
+		// Behind non-zero BG, but over BG colour 0
+		assert_eq!(disp.framebuffer[4], 1);
+		assert_eq!(disp.framebuffer[8], 3);
+	}
+}
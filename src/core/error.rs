@@ -0,0 +1,47 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use super::header::HeaderMismatch;
+
+#[derive(Debug)]
+pub enum EmuError {
+	IoError(io::Error),
+	RomTooLarge(usize),
+	RomTooSmall(usize), // Not even long enough to hold a header
+	BadHeader(Vec<HeaderMismatch>), // Only the problems that stop the cart from being mapped
+	IllegalOpcode { opcode: u8, pc: u16 },
+}
+
+impl fmt::Display for EmuError {
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			EmuError::IoError(ref e) => write!(f, "I/O error: {}", e),
+			EmuError::RomTooLarge(len) => write!(f, "ROM is {} bytes, larger than any cartridge", len),
+			EmuError::RomTooSmall(len) => write!(f, "ROM is {} bytes, too small to hold a header", len),
+			EmuError::BadHeader(ref mismatches) => {
+				write!(f, "Bad cartridge header")?;
+				for mismatch in mismatches {
+					write!(f, "; {}", mismatch)?;
+				}
+				Ok(())
+			}
+			EmuError::IllegalOpcode { opcode, pc } => write!(f, "Illegal opcode {:02X} at {:04X}", opcode, pc),
+		}
+	}
+}
+
+impl Error for EmuError {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match *self {
+			EmuError::IoError(ref e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
+impl From<io::Error> for EmuError {
+	fn from(e:io::Error) -> EmuError {
+		EmuError::IoError(e)
+	}
+}
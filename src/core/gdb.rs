@@ -0,0 +1,393 @@
+// GDB remote serial protocol server so external debuggers can drive the CPU.
+// The register file is sent as AF, BC, DE, HL, SP, PC, each 16 bits little-endian.
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use super::debugger::{Debugger, StopReason, POLL_INTERVAL};
+use super::error::EmuError;
+use super::registers::WORD_NAMES as REGISTERS;
+use super::watch::{Watchpoint, WatchKind};
+use super::Core;
+
+// Largest packet we accept and send, advertised in qSupported
+const PACKET_SIZE: usize = 0x4000;
+
+#[derive(Debug, PartialEq)]
+pub enum Response {
+	Reply(String),
+	Close,
+}
+
+pub struct GdbStub {
+	pub debugger: Debugger,
+}
+
+fn checksum(data:&str) -> u8 {
+	data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+pub fn frame(data:&str) -> String {
+	format!("${}#{:02x}", data, checksum(data))
+}
+
+fn hex_bytes(text:&str) -> Option<Vec<u8>> {
+	if text.len() % 2 != 0 {
+		return None;
+	}
+	(0 .. text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i .. i + 2], 16).ok()).collect()
+}
+
+// "addr,len" as used by m, M and the Z packets
+fn addr_len(text:&str) -> Option<(u16, usize)> {
+	let mut parts = text.splitn(2, ',');
+	let addr = u16::from_str_radix(parts.next()?, 16).ok()?;
+	let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+	Some((addr, len))
+}
+
+impl GdbStub {
+	pub fn create() -> GdbStub {
+		GdbStub {
+			debugger: Debugger::create()
+		}
+	}
+
+	fn stop_reply(reason:StopReason) -> String {
+		match reason {
+			StopReason::Watchpoint(hit) => {
+				let kind = match hit.kind {
+					WatchKind::Read => "rwatch",
+					WatchKind::Access => "awatch",
+					_ => "watch",
+				};
+				format!("T05{}:{:04x};", kind, hit.addr)
+			}
+			// SIGILL, the CPU will never execute again
+			StopReason::Locked { .. } => "S04".to_string(),
+			StopReason::Interrupted => "S02".to_string(),
+			_ => "S05".to_string(),
+		}
+	}
+
+	fn read_registers(core:&Core) -> String {
+		REGISTERS.iter()
+			.map(|name| core.reg.read_named(name).unwrap_or(0))
+			.map(|val| format!("{:02x}{:02x}", val as u8, val >> 8))
+			.collect()
+	}
+
+	fn write_registers(core:&mut Core, data:&str) -> String {
+		match hex_bytes(data) {
+			Some(ref bytes) if bytes.len() == REGISTERS.len() * 2 => {
+				for (i, name) in REGISTERS.iter().enumerate() {
+					core.reg.write_named(name, bytes[i * 2] as u16 | (bytes[i * 2 + 1] as u16) << 8);
+				}
+				"OK".to_string()
+			}
+			_ => "E01".to_string(),
+		}
+	}
+
+	fn write_register(core:&mut Core, data:&str) -> String {
+		let mut parts = data.splitn(2, '=');
+		let index = parts.next().and_then(|i| usize::from_str_radix(i, 16).ok());
+		let bytes = parts.next().and_then(hex_bytes);
+		match (index, bytes) {
+			(Some(index), Some(ref bytes)) if index < REGISTERS.len() && bytes.len() == 2 => {
+				core.reg.write_named(REGISTERS[index], bytes[0] as u16 | (bytes[1] as u16) << 8);
+				"OK".to_string()
+			}
+			_ => "E01".to_string(),
+		}
+	}
+
+	fn read_memory(core:&Core, data:&str) -> String {
+		match addr_len(data) {
+			// Each byte is two hex digits of the reply
+			Some((addr, len)) if len <= PACKET_SIZE / 2 => {
+				(0 .. len).map(|i| format!("{:02x}", core.mem.peek(addr.wrapping_add(i as u16)))).collect()
+			}
+			_ => "E01".to_string(),
+		}
+	}
+
+	fn write_memory(core:&mut Core, data:&str) -> String {
+		let mut parts = data.splitn(2, ':');
+		let target = parts.next().and_then(addr_len);
+		let bytes = parts.next().and_then(hex_bytes);
+		match (target, bytes) {
+			(Some((addr, len)), Some(bytes)) if bytes.len() == len => {
+				for (i, val) in bytes.iter().enumerate() {
+					core.mem.set_mem(addr.wrapping_add(i as u16), *val);
+				}
+				"OK".to_string()
+			}
+			_ => "E01".to_string(),
+		}
+	}
+
+	// Z and z packets: type 0 and 1 are breakpoints, 2 write, 3 read and 4 access watchpoints
+	fn set_point(&mut self, core:&mut Core, data:&str, insert:bool) -> String {
+		let mut parts = data.splitn(2, ',');
+		let kind = parts.next().unwrap_or("");
+		let (addr, len) = match parts.next().and_then(addr_len) {
+			Some((addr, len)) => (addr, len.max(1)),
+			None => return "E01".to_string(),
+		};
+		let kind = match kind {
+			"0" | "1" => {
+				if insert {
+					self.debugger.add_breakpoint(addr, None);
+				} else {
+					self.debugger.breakpoints.retain(|bp| bp.addr != addr || bp.condition.is_some());
+				}
+				return "OK".to_string();
+			}
+			"2" => WatchKind::Write,
+			"3" => WatchKind::Read,
+			"4" => WatchKind::Access,
+			_ => return String::new(),
+		};
+		let wp = Watchpoint { start: addr, end: addr.wrapping_add(len as u16 - 1), kind };
+		if insert {
+			core.mem.watchpoints.push(wp);
+		} else if let Some(index) = core.mem.watchpoints.iter().position(|w| *w == wp) {
+			core.mem.watchpoints.remove(index);
+		}
+		"OK".to_string()
+	}
+
+	// Runs until something stops the CPU, polling interrupted between batches of instructions
+	fn resume(&mut self, core:&mut Core, interrupted:&mut dyn FnMut() -> bool) -> String {
+		loop {
+			match self.debugger.run(core, Some(POLL_INTERVAL)) {
+				// The client can interrupt a HALT or STOP itself, so those don't end the continue
+				Ok(StopReason::Done) | Ok(StopReason::Halted) | Ok(StopReason::Stopped) => {
+					if interrupted() {
+						return "S02".to_string();
+					}
+				}
+				Ok(reason) => return GdbStub::stop_reply(reason),
+				Err(EmuError::IoError(_)) => return "E05".to_string(),
+				Err(_) => return "S05".to_string(),
+			}
+		}
+	}
+
+	// Answers one packet's payload, without the framing
+	pub fn process(&mut self, core:&mut Core, packet:&str, interrupted:&mut dyn FnMut() -> bool) -> Response {
+		let (cmd, data) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+		let reply = match cmd {
+			"?" => "S05".to_string(),
+			"g" => GdbStub::read_registers(core),
+			"G" => GdbStub::write_registers(core, data),
+			"p" => match usize::from_str_radix(data, 16) {
+				Ok(index) if index < REGISTERS.len() => {
+					let val = core.reg.read_named(REGISTERS[index]).unwrap_or(0);
+					format!("{:02x}{:02x}", val as u8, val >> 8)
+				}
+				_ => "E01".to_string(),
+			},
+			"P" => GdbStub::write_register(core, data),
+			"m" => GdbStub::read_memory(core, data),
+			"M" => GdbStub::write_memory(core, data),
+			"Z" => self.set_point(core, data, true),
+			"z" => self.set_point(core, data, false),
+			"s" => match self.debugger.run(core, Some(1)) {
+				Ok(reason) => GdbStub::stop_reply(reason),
+				Err(_) => "E05".to_string(),
+			},
+			"c" => self.resume(core, interrupted),
+			"H" => "OK".to_string(),
+			"q" if data.starts_with("Supported") => format!("PacketSize={:x}", PACKET_SIZE),
+			"q" if data == "Attached" => "1".to_string(),
+			"q" if data == "C" => "QC1".to_string(),
+			"D" | "k" => return Response::Close,
+			// An empty reply tells the client the packet isn't supported
+			_ => String::new(),
+		};
+		Response::Reply(reply)
+	}
+
+	fn send(stream:&mut TcpStream, data:&str) -> io::Result<()> {
+		stream.write_all(frame(data).as_bytes())?;
+		stream.flush()
+	}
+
+	// Serves one client on a connected stream until it detaches or disconnects
+	pub fn serve(&mut self, core:&mut Core, stream:&mut TcpStream) -> io::Result<()> {
+		let mut poll_stream = stream.try_clone()?;
+		// A lone 0x03 byte while the target runs asks it to stop
+		let mut interrupted = move || {
+			let mut byte = [0u8];
+			if poll_stream.set_nonblocking(true).is_err() {
+				return false;
+			}
+			let got = poll_stream.read(&mut byte);
+			let _ = poll_stream.set_nonblocking(false);
+			matches!(got, Ok(1) if byte[0] == 0x03)
+		};
+		let mut packet = Vec::new();
+		let mut in_packet = false;
+		let mut byte = [0u8];
+		loop {
+			if stream.read(&mut byte)? == 0 {
+				return Ok(());
+			}
+			match byte[0] {
+				b'$' if !in_packet => {
+					in_packet = true;
+					packet.clear();
+				}
+				b'#' if in_packet => {
+					in_packet = false;
+					let mut sum = [0u8; 2];
+					stream.read_exact(&mut sum)?;
+					let text = String::from_utf8_lossy(&packet).into_owned();
+					let expected = std::str::from_utf8(&sum).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+					if expected != Some(checksum(&text)) {
+						stream.write_all(b"-")?;
+						continue;
+					}
+					stream.write_all(b"+")?;
+					match self.process(core, &text, &mut interrupted) {
+						Response::Reply(reply) => GdbStub::send(stream, &reply)?,
+						Response::Close => {
+							// Detach expects an answer, kill doesn't
+							if text.starts_with('D') {
+								GdbStub::send(stream, "OK")?;
+							}
+							return Ok(());
+						}
+					}
+				}
+				0x03 if !in_packet => GdbStub::send(stream, "S02")?,
+				_ if in_packet => packet.push(byte[0]),
+				_ => {} // Acks from the client
+			}
+		}
+	}
+
+	// Waits for a single client on a local port, e.g. "127.0.0.1:2345"
+	pub fn listen(&mut self, core:&mut Core, addr:&str) -> io::Result<()> {
+		let listener = TcpListener::bind(addr)?;
+		let (mut stream, _) = listener.accept()?;
+		stream.set_nodelay(true)?;
+		self.serve(core, &mut stream)
+	}
+}
+
+mod test {
+	#[cfg(test)]
+	use super::super::test::core_with_program;
+
+	#[cfg(test)]
+	fn reply(stub:&mut super::GdbStub, core:&mut super::Core, packet:&str) -> String {
+		match stub.process(core, packet, &mut || false) {
+			super::Response::Reply(reply) => reply,
+			super::Response::Close => panic!("Unexpected close"),
+		}
+	}
+
+	#[test]
+	fn test_frame() {
+		assert_eq!(super::frame("OK"), "$OK#9a");
+		assert_eq!(super::frame(""), "$#00");
+	}
+
+	#[test]
+	fn test_registers() {
+		use super::*;
+		let mut core = core_with_program(&[0x00]);
+		let mut stub = GdbStub::create();
+		assert_eq!(reply(&mut stub, &mut core, "g"), "b0011300d8004d01feff00c0");
+		assert_eq!(reply(&mut stub, &mut core, "P3=3412"), "OK");
+		assert_eq!(core.reg.get_hl(), 0x1234);
+		assert_eq!(reply(&mut stub, &mut core, "p5"), "00c0");
+		assert_eq!(reply(&mut stub, &mut core, "G000100020003000400050006"), "OK");
+		assert_eq!(core.reg.get_af(), 0x0100);
+		assert_eq!(core.reg.get_bc(), 0x0200);
+		assert_eq!(core.reg.pc, 0x0600);
+	}
+
+	#[test]
+	fn test_memory() {
+		use super::*;
+		let mut core = core_with_program(&[0x3E, 0x42]);
+		let mut stub = GdbStub::create();
+		assert_eq!(reply(&mut stub, &mut core, "mc000,2"), "3e42");
+		assert_eq!(reply(&mut stub, &mut core, "Mc010,2:abcd"), "OK");
+		assert_eq!(core.mem.get_mem(0xC011), 0xCD);
+		assert_eq!(reply(&mut stub, &mut core, "Mc010,2:ab"), "E01");
+		assert_eq!(reply(&mut stub, &mut core, "m0,ffffffff"), "E01");
+		assert_eq!(reply(&mut stub, &mut core, "m0,2000").len(), 0x4000);
+	}
+
+	#[test]
+	fn test_breakpoint_and_step() {
+		use super::*;
+		// INC A; INC A; JR -4
+		let mut core = core_with_program(&[0x3C, 0x3C, 0x18, 0xFC]);
+		let mut stub = GdbStub::create();
+		assert_eq!(reply(&mut stub, &mut core, "s"), "S05");
+		assert_eq!(core.reg.pc, 0xC001);
+		assert_eq!(reply(&mut stub, &mut core, "Z0,c002,1"), "OK");
+		assert_eq!(reply(&mut stub, &mut core, "c"), "S05");
+		assert_eq!(core.reg.pc, 0xC002);
+		assert_eq!(reply(&mut stub, &mut core, "z0,c002,1"), "OK");
+		assert!(stub.debugger.breakpoints.is_empty());
+		// With nothing to stop it, continue only returns when the client interrupts
+		match stub.process(&mut core, "c", &mut || true) {
+			Response::Reply(reply) => assert_eq!(reply, "S02"),
+			Response::Close => panic!("Unexpected close"),
+		}
+	}
+
+	#[test]
+	fn test_watchpoint() {
+		use super::*;
+		// LD HL,$C100; LD (HL),A
+		let mut core = core_with_program(&[0x21, 0x00, 0xC1, 0x77, 0x00]);
+		let mut stub = GdbStub::create();
+		assert_eq!(reply(&mut stub, &mut core, "Z2,c100,1"), "OK");
+		assert_eq!(reply(&mut stub, &mut core, "c"), "T05watch:c100;");
+		assert_eq!(core.reg.pc, 0xC004);
+		assert_eq!(reply(&mut stub, &mut core, "z2,c100,1"), "OK");
+		assert!(core.mem.watchpoints.is_empty());
+	}
+
+	#[test]
+	fn test_access_watchpoint() {
+		use super::*;
+		// LD A,($C100)
+		let mut core = core_with_program(&[0xFA, 0x00, 0xC1, 0x00]);
+		let mut stub = GdbStub::create();
+		assert_eq!(reply(&mut stub, &mut core, "Z4,c100,1"), "OK");
+		assert_eq!(reply(&mut stub, &mut core, "c"), "T05awatch:c100;");
+		assert_eq!(reply(&mut stub, &mut core, "z4,c100,1"), "OK");
+		assert!(core.mem.watchpoints.is_empty());
+	}
+
+	#[test]
+	fn test_over_tcp() {
+		use super::*;
+		use std::net::TcpListener;
+		use std::thread;
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let client = thread::spawn(move || {
+			let mut stream = TcpStream::connect(addr).unwrap();
+			stream.write_all(b"$mc000,1#bd$D#44").unwrap();
+			let mut received = String::new();
+			stream.read_to_string(&mut received).unwrap();
+			received
+		});
+		let (mut stream, _) = listener.accept().unwrap();
+		let mut core = core_with_program(&[0x3E]);
+		GdbStub::create().serve(&mut core, &mut stream).unwrap();
+		drop(stream);
+		assert_eq!(client.join().unwrap(), "+$3e#98+$OK#9a");
+	}
+}
@@ -0,0 +1,239 @@
+// The cartridge header at 0x0100-0x014F describes the game and the hardware on the cart
+
+use std::fmt;
+
+pub const LOGO: [u8; 48] = [
+	0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+	0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+	0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CartridgeHeader {
+	pub title: String,
+	pub manufacturer: String, // Only set on later carts, which shortened the title to make room
+	pub cgb_flag: u8,
+	pub new_licensee: String,
+	pub sgb_flag: u8,
+	pub cart_type: u8,
+	pub rom_size: u8,
+	pub ram_size: u8,
+	pub old_licensee: u8, // 0x33 means the new licensee code is used instead
+	pub version: u8,
+	pub header_checksum: u8,
+	pub global_checksum: u16,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HeaderMismatch {
+	Logo,
+	HeaderChecksum { expected: u8, actual: u8 },
+	GlobalChecksum { expected: u16, actual: u16 },
+	RomSize { expected: usize, actual: usize },
+	UnknownRomSize(u8),
+	UnsupportedCartType(u8),
+}
+
+impl fmt::Display for HeaderMismatch {
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			HeaderMismatch::Logo => write!(f, "Nintendo logo doesn't match"),
+			HeaderMismatch::HeaderChecksum { expected, actual } =>
+				write!(f, "Header checksum is {:02X} but the header sums to {:02X}", expected, actual),
+			HeaderMismatch::GlobalChecksum { expected, actual } =>
+				write!(f, "Global checksum is {:04X} but the ROM sums to {:04X}", expected, actual),
+			HeaderMismatch::RomSize { expected, actual } =>
+				write!(f, "Header declares {} bytes of ROM but the file has {}", expected, actual),
+			HeaderMismatch::UnknownRomSize(code) => write!(f, "Unknown ROM size code {:02X}", code),
+			HeaderMismatch::UnsupportedCartType(cart_type) => write!(f, "Unsupported cartridge type {:02X}", cart_type),
+		}
+	}
+}
+
+impl HeaderMismatch {
+	// Checksums and the logo are only checked by the boot ROM, so carts that get them wrong still run
+	pub fn is_fatal(&self) -> bool {
+		matches!(*self, HeaderMismatch::UnknownRomSize(_) | HeaderMismatch::UnsupportedCartType(_))
+	}
+}
+
+// Printable ASCII up to the first NUL
+fn ascii(bytes:&[u8]) -> String {
+	bytes.iter()
+		.take_while(|&&b| b != 0)
+		.filter(|b| b.is_ascii_graphic() || **b == b' ')
+		.map(|&b| b as char)
+		.collect()
+}
+
+impl CartridgeHeader {
+	// data must cover at least 0x0000-0x014F
+	pub fn parse(data:&[u8]) -> CartridgeHeader {
+		let cgb_flag = data[0x0143];
+		let manufacturer = &data[0x013F .. 0x0143];
+		let has_manufacturer = cgb_flag & 0x80 != 0 && manufacturer.iter().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit());
+		let title_end = if has_manufacturer { 0x013F } else if cgb_flag & 0x80 != 0 { 0x0143 } else { 0x0144 };
+		CartridgeHeader {
+			title: ascii(&data[0x0134 .. title_end]),
+			manufacturer: if has_manufacturer { ascii(manufacturer) } else { String::new() },
+			cgb_flag,
+			new_licensee: ascii(&data[0x0144 .. 0x0146]),
+			sgb_flag: data[0x0146],
+			cart_type: data[0x0147],
+			rom_size: data[0x0148],
+			ram_size: data[0x0149],
+			old_licensee: data[0x014B],
+			version: data[0x014C],
+			header_checksum: data[0x014D],
+			global_checksum: ((data[0x014E] as u16) << 8) | data[0x014F] as u16,
+		}
+	}
+
+	pub fn supports_cgb(&self) -> bool {
+		self.cgb_flag & 0x80 != 0
+	}
+
+	pub fn cgb_only(&self) -> bool {
+		self.cgb_flag == 0xC0
+	}
+
+	pub fn supports_sgb(&self) -> bool {
+		self.sgb_flag == 0x03
+	}
+
+	pub fn rom_bytes(&self) -> Option<usize> {
+		match self.rom_size {
+			0x00 ..= 0x08 => Some(0x8000 << self.rom_size),
+			_ => None,
+		}
+	}
+
+	pub fn ram_bytes(&self) -> usize {
+		match self.ram_size {
+			0x01 => 0x800,
+			0x02 => 0x2000,
+			0x03 => 0x8000,
+			0x04 => 0x20000,
+			0x05 => 0x10000,
+			_ => 0,
+		}
+	}
+
+	pub fn has_battery(&self) -> bool {
+		matches!(self.cart_type, 0x03 | 0x06 | 0x09 | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E)
+	}
+
+	// The boot ROM refuses to start unless this matches the stored checksum
+	pub fn compute_header_checksum(data:&[u8]) -> u8 {
+		data[0x0134 ..= 0x014C].iter().fold(0u8, |sum, &b| sum.wrapping_sub(b).wrapping_sub(1))
+	}
+
+	// Every byte except the checksum itself, which real hardware never verifies
+	pub fn compute_global_checksum(data:&[u8]) -> u16 {
+		data.iter().enumerate()
+			.filter(|&(i, _)| i != 0x014E && i != 0x014F)
+			.fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16))
+	}
+
+	pub fn validate(&self, data:&[u8]) -> Vec<HeaderMismatch> {
+		let mut mismatches = Vec::new();
+		if data[0x0104 .. 0x0134] != LOGO[..] {
+			mismatches.push(HeaderMismatch::Logo);
+		}
+		let actual = CartridgeHeader::compute_header_checksum(data);
+		if actual != self.header_checksum {
+			mismatches.push(HeaderMismatch::HeaderChecksum { expected: self.header_checksum, actual });
+		}
+		let actual = CartridgeHeader::compute_global_checksum(data);
+		if actual != self.global_checksum {
+			mismatches.push(HeaderMismatch::GlobalChecksum { expected: self.global_checksum, actual });
+		}
+		match self.rom_bytes() {
+			Some(expected) if expected != data.len() =>
+				mismatches.push(HeaderMismatch::RomSize { expected, actual: data.len() }),
+			Some(_) => {}
+			None => mismatches.push(HeaderMismatch::UnknownRomSize(self.rom_size)),
+		}
+		match self.cart_type {
+			0x00 ..= 0x03 | 0x05 | 0x06 | 0x08 | 0x09 | 0x0F ..= 0x13 | 0x19 ..= 0x1E => {}
+			_ => mismatches.push(HeaderMismatch::UnsupportedCartType(self.cart_type)),
+		}
+		mismatches
+	}
+}
+
+mod test {
+	#[cfg(test)]
+	fn valid_rom() -> Vec<u8> {
+		use super::*;
+		let mut data = vec![0; 0x8000];
+		data[0x0104 .. 0x0134].copy_from_slice(&LOGO);
+		data[0x0134 .. 0x0139].copy_from_slice(b"TETRI");
+		data[0x0147] = 0x01;
+		data[0x014D] = CartridgeHeader::compute_header_checksum(&data);
+		let global = CartridgeHeader::compute_global_checksum(&data);
+		data[0x014E] = (global >> 8) as u8;
+		data[0x014F] = global as u8;
+		data
+	}
+
+	#[test]
+	fn test_parse() {
+		use super::*;
+		let mut data = valid_rom();
+		data[0x0146] = 0x03;
+		data[0x0149] = 0x02;
+		data[0x014B] = 0x33;
+		data[0x0144 .. 0x0146].copy_from_slice(b"01");
+		let header = CartridgeHeader::parse(&data);
+		assert_eq!(header.title, "TETRI");
+		assert_eq!(header.manufacturer, "");
+		assert_eq!(header.new_licensee, "01");
+		assert_eq!(header.cart_type, 0x01);
+		assert_eq!(header.ram_bytes(), 0x2000);
+		assert_eq!(header.rom_bytes(), Some(0x8000));
+		assert!(header.supports_sgb());
+		assert!(!header.supports_cgb());
+	}
+
+	#[test]
+	fn test_cgb_title() {
+		use super::*;
+		let mut data = valid_rom();
+		data[0x0134 .. 0x013F].copy_from_slice(b"POKEMON_GLD");
+		data[0x013F .. 0x0143].copy_from_slice(b"AAUE");
+		data[0x0143] = 0x80;
+		let header = CartridgeHeader::parse(&data);
+		assert_eq!(header.title, "POKEMON_GLD");
+		assert_eq!(header.manufacturer, "AAUE");
+		assert!(header.supports_cgb());
+		assert!(!header.cgb_only());
+	}
+
+	#[test]
+	fn test_validate() {
+		use super::*;
+		let data = valid_rom();
+		let header = CartridgeHeader::parse(&data);
+		assert!(header.validate(&data).is_empty());
+
+		let mut data = valid_rom();
+		data[0x0104] = 0x00;
+		data[0x0134] = b'B';
+		data[0x0147] = 0xFC;
+		let header = CartridgeHeader::parse(&data);
+		let mismatches = header.validate(&data);
+		assert_eq!(mismatches.len(), 4);
+		assert_eq!(mismatches[0], HeaderMismatch::Logo);
+		match mismatches[1] {
+			HeaderMismatch::HeaderChecksum { expected, actual } => assert!(expected != actual),
+			_ => panic!("Expected a header checksum mismatch"),
+		}
+		assert_eq!(mismatches[3], HeaderMismatch::UnsupportedCartType(0xFC));
+
+		let mut data = valid_rom();
+		data.resize(0x10000, 0);
+		let header = CartridgeHeader::parse(&data);
+		assert_eq!(header.validate(&data), vec![HeaderMismatch::RomSize { expected: 0x8000, actual: 0x10000 }]);
+	}
+}
@@ -1,15 +1,136 @@
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interrupt {
+	VBlank,
+	Stat,
+	Timer,
+	Serial,
+	Joypad
+}
+
+// Highest priority first, matching the bit order in IE and IF
+pub const PRIORITY: [Interrupt; 5] = [
+	Interrupt::VBlank,
+	Interrupt::Stat,
+	Interrupt::Timer,
+	Interrupt::Serial,
+	Interrupt::Joypad
+];
+
+impl Interrupt {
+	pub fn bit(self) -> u8 {
+		match self {
+			Interrupt::VBlank => 0x01,
+			Interrupt::Stat => 0x02,
+			Interrupt::Timer => 0x04,
+			Interrupt::Serial => 0x08,
+			Interrupt::Joypad => 0x10,
+		}
+	}
+
+	pub fn vector(self) -> u16 {
+		match self {
+			Interrupt::VBlank => 0x40,
+			Interrupt::Stat => 0x48,
+			Interrupt::Timer => 0x50,
+			Interrupt::Serial => 0x58,
+			Interrupt::Joypad => 0x60,
+		}
+	}
+}
+
 pub struct Interrupts {
-	pub enabled: bool,
+	pub enabled: bool, // IME
+	pub ie: u8, // 0xFFFF
+	pub iflag: u8, // 0xFF0F
+	pub ei_pending: bool, // EI takes effect after the following instruction
 }
 
 impl Interrupts {
 	pub fn create() -> Interrupts {
 		Interrupts {
-			enabled: true
+			enabled: false,
+			ie: 0x00,
+			iflag: 0x01,
+			ei_pending: false
 		}
 	}
 
 	pub fn toggle(&mut self, en:bool) {
 		self.enabled = en;
+		self.ei_pending = false;
+	}
+
+	pub fn schedule_enable(&mut self) {
+		self.ei_pending = true;
+	}
+
+	pub fn request(&mut self, int:Interrupt) {
+		self.iflag |= int.bit();
+	}
+
+	pub fn clear(&mut self, int:Interrupt) {
+		self.iflag &= !int.bit();
+	}
+
+	// Any interrupt that is both enabled and requested, regardless of IME
+	pub fn pending(&self) -> bool {
+		self.ie & self.iflag & 0x1F != 0
+	}
+
+	pub fn highest_pending(&self) -> Option<Interrupt> {
+		PRIORITY.iter().cloned().find(|int| self.ie & self.iflag & int.bit() != 0)
+	}
+
+	pub fn get_mem(&self, loc:u16) -> u8 {
+		match loc {
+			0xFF0F => self.iflag | 0xE0,
+			_ => self.ie,
+		}
+	}
+
+	pub fn set_mem(&mut self, loc:u16, val:u8) {
+		match loc {
+			0xFF0F => self.iflag = val & 0x1F,
+			_ => self.ie = val,
+		}
 	}
-}
\ No newline at end of file
+}
+
+mod test {
+	#[test]
+	fn test_priority() {
+		use super::Interrupt;
+		let mut int = super::Interrupts::create();
+		int.iflag = 0;
+		assert_eq!(int.highest_pending(), None);
+		int.request(Interrupt::Joypad);
+		int.request(Interrupt::Timer);
+		assert_eq!(int.highest_pending(), None);
+		int.ie = 0x1F;
+		assert_eq!(int.highest_pending(), Some(Interrupt::Timer));
+		int.clear(Interrupt::Timer);
+		assert_eq!(int.highest_pending(), Some(Interrupt::Joypad));
+	}
+
+	#[test]
+	fn test_registers() {
+		let mut int = super::Interrupts::create();
+		int.set_mem(0xFF0F, 0xFF);
+		assert_eq!(int.iflag, 0x1F);
+		assert_eq!(int.get_mem(0xFF0F), 0xFF);
+		int.set_mem(0xFF0F, 0x04);
+		assert_eq!(int.get_mem(0xFF0F), 0xE4);
+		int.set_mem(0xFFFF, 0x05);
+		assert_eq!(int.get_mem(0xFFFF), 0x05);
+	}
+
+	#[test]
+	fn test_ei_delay_flag() {
+		let mut int = super::Interrupts::create();
+		int.schedule_enable();
+		assert!(!int.enabled);
+		assert!(int.ei_pending);
+		int.toggle(false);
+		assert!(!int.ei_pending);
+	}
+}
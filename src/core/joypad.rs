@@ -0,0 +1,138 @@
+use super::interrupts::{Interrupts, Interrupt};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+	Right,
+	Left,
+	Up,
+	Down,
+	A,
+	B,
+	Select,
+	Start
+}
+
+impl Button {
+	// Bit in the pressed mask, directions in the low nibble and actions in the high one
+	fn bit(self) -> u8 {
+		match self {
+			Button::Right => 0x01,
+			Button::Left => 0x02,
+			Button::Up => 0x04,
+			Button::Down => 0x08,
+			Button::A => 0x10,
+			Button::B => 0x20,
+			Button::Select => 0x40,
+			Button::Start => 0x80,
+		}
+	}
+}
+
+pub struct Joypad {
+	pub select: u8, // P1 bits 4-5, a 0 selects that half of the matrix
+	pub pressed: u8,
+}
+
+impl Joypad {
+	pub fn create() -> Joypad {
+		Joypad {
+			select: 0x30,
+			pressed: 0x00
+		}
+	}
+
+	// The four input lines as seen in the low nibble of P1, 0 meaning pressed
+	fn lines(&self) -> u8 {
+		let mut low = 0x00;
+		if self.select & 0x10 == 0 {
+			low |= self.pressed & 0x0F;
+		}
+		if self.select & 0x20 == 0 {
+			low |= self.pressed >> 4;
+		}
+		!low & 0x0F
+	}
+
+	// Requests the joypad interrupt if any line fell since `before`
+	fn check_edge(&self, before:u8, int:&mut Interrupts) {
+		if before & !self.lines() != 0 {
+			int.request(Interrupt::Joypad);
+		}
+	}
+
+	pub fn press(&mut self, button:Button, int:&mut Interrupts) {
+		let before = self.lines();
+		self.pressed |= button.bit();
+		self.check_edge(before, int);
+	}
+
+	pub fn release(&mut self, button:Button) {
+		self.pressed &= !button.bit();
+	}
+
+	pub fn get_mem(&self) -> u8 {
+		0xC0 | self.select | self.lines()
+	}
+
+	pub fn set_mem(&mut self, val:u8, int:&mut Interrupts) {
+		let before = self.lines();
+		self.select = val & 0x30;
+		self.check_edge(before, int);
+	}
+}
+
+mod test {
+	#[test]
+	fn test_nothing_selected() {
+		use super::*;
+		let mut joypad = Joypad::create();
+		let mut int = Interrupts::create();
+		joypad.press(Button::A, &mut int);
+		joypad.press(Button::Down, &mut int);
+		assert_eq!(joypad.get_mem(), 0xFF);
+	}
+
+	#[test]
+	fn test_select_lines() {
+		use super::*;
+		let mut joypad = Joypad::create();
+		let mut int = Interrupts::create();
+		joypad.press(Button::Start, &mut int);
+		joypad.press(Button::Left, &mut int);
+		joypad.set_mem(0x20, &mut int);
+		assert_eq!(joypad.get_mem(), 0xED);
+		joypad.set_mem(0x10, &mut int);
+		assert_eq!(joypad.get_mem(), 0xD7);
+		joypad.set_mem(0x00, &mut int);
+		assert_eq!(joypad.get_mem(), 0xC5);
+		joypad.release(Button::Start);
+		assert_eq!(joypad.get_mem(), 0xCD);
+	}
+
+	#[test]
+	fn test_interrupt_on_press() {
+		use super::*;
+		let mut joypad = Joypad::create();
+		let mut int = Interrupts::create();
+		int.iflag = 0;
+		joypad.set_mem(0x20, &mut int);
+		// Actions aren't selected so their lines stay high
+		joypad.press(Button::B, &mut int);
+		assert_eq!(int.iflag, 0);
+		joypad.press(Button::Up, &mut int);
+		assert_eq!(int.iflag, Interrupt::Joypad.bit());
+	}
+
+	#[test]
+	fn test_interrupt_on_select() {
+		use super::*;
+		let mut joypad = Joypad::create();
+		let mut int = Interrupts::create();
+		int.iflag = 0;
+		joypad.press(Button::Select, &mut int);
+		assert_eq!(int.iflag, 0);
+		// Selecting a half with a held button pulls its line low
+		joypad.set_mem(0x10, &mut int);
+		assert_eq!(int.iflag, Interrupt::Joypad.bit());
+	}
+}
@@ -0,0 +1,132 @@
+// Leveled logging per subsystem, everything is off until a frontend turns it on
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+	Off,
+	Error,
+	Warn,
+	Info,
+	Debug,
+	Trace,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+	Cpu,
+	Mem,
+	Ppu,
+	Timer,
+}
+
+pub const TARGETS: [Target; 4] = [Target::Cpu, Target::Mem, Target::Ppu, Target::Timer];
+const LEVELS: [Level; 6] = [Level::Off, Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace];
+
+static ENABLED: [AtomicUsize; 4] = [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)];
+
+impl Level {
+	fn name(self) -> &'static str {
+		match self {
+			Level::Off => "off",
+			Level::Error => "error",
+			Level::Warn => "warn",
+			Level::Info => "info",
+			Level::Debug => "debug",
+			Level::Trace => "trace",
+		}
+	}
+
+	pub fn parse(name:&str) -> Option<Level> {
+		LEVELS.iter().cloned().find(|level| level.name() == name.to_lowercase())
+	}
+}
+
+impl Target {
+	fn name(self) -> &'static str {
+		match self {
+			Target::Cpu => "cpu",
+			Target::Mem => "mem",
+			Target::Ppu => "ppu",
+			Target::Timer => "timer",
+		}
+	}
+
+	pub fn parse(name:&str) -> Option<Target> {
+		TARGETS.iter().cloned().find(|target| target.name() == name.to_lowercase())
+	}
+}
+
+pub fn set_level(target:Target, level:Level) {
+	ENABLED[target as usize].store(level as usize, Ordering::Relaxed);
+}
+
+pub fn level(target:Target) -> Level {
+	LEVELS[ENABLED[target as usize].load(Ordering::Relaxed)]
+}
+
+pub fn enabled(target:Target, level:Level) -> bool {
+	level != Level::Off && level as usize <= ENABLED[target as usize].load(Ordering::Relaxed)
+}
+
+// Applies a spec like "warn,cpu=trace", where a bare level sets every target
+pub fn configure(spec:&str) -> Result<(), String> {
+	for part in spec.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+		let mut pieces = part.splitn(2, '=');
+		let first = pieces.next().unwrap_or("");
+		match pieces.next() {
+			Some(level) => {
+				let target = Target::parse(first).ok_or(format!("Unknown log target {}", first))?;
+				let level = Level::parse(level).ok_or(format!("Unknown log level {}", level))?;
+				set_level(target, level);
+			}
+			None => {
+				let level = Level::parse(first).ok_or(format!("Unknown log level {}", first))?;
+				for target in TARGETS.iter() {
+					set_level(*target, level);
+				}
+			}
+		}
+	}
+	Ok(())
+}
+
+pub fn write(target:Target, level:Level, args:fmt::Arguments) {
+	eprintln!("[{} {}] {}", target.name(), level.name(), args);
+}
+
+// Arguments are only formatted when the target is enabled at that level
+macro_rules! log {
+	($target:ident, $level:ident, $($arg:tt)+) => {
+		if $crate::core::log::enabled($crate::core::log::Target::$target, $crate::core::log::Level::$level) {
+			$crate::core::log::write($crate::core::log::Target::$target, $crate::core::log::Level::$level, format_args!($($arg)+));
+		}
+	}
+}
+
+mod test {
+	#[test]
+	fn test_configure() {
+		use super::*;
+		// Only the timer target is touched, so tests running alongside can't be affected
+		assert!(!enabled(Target::Timer, Level::Error));
+		configure("timer=debug").unwrap();
+		assert!(enabled(Target::Timer, Level::Info));
+		assert!(enabled(Target::Timer, Level::Debug));
+		assert!(!enabled(Target::Timer, Level::Trace));
+		assert!(configure("timer=loud").is_err());
+		assert!(configure("gpu=info").is_err());
+		set_level(Target::Timer, Level::Off);
+		assert_eq!(level(Target::Timer), Level::Off);
+		assert!(!enabled(Target::Timer, Level::Off));
+	}
+
+	#[test]
+	fn test_parse() {
+		use super::*;
+		assert_eq!(Level::parse("TRACE"), Some(Level::Trace));
+		assert_eq!(Target::parse("ppu"), Some(Target::Ppu));
+		assert_eq!(Target::parse("apu"), None);
+	}
+}
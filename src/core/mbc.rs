@@ -0,0 +1,600 @@
+// Bank controllers sit between the CPU and the cartridge's ROM and RAM chips
+
+use super::rtc::Rtc;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+pub struct Mbc1 {
+	pub ram_enabled: bool,
+	pub bank1: u8, // 5-bit ROM bank register, 0x2000-0x3FFF
+	pub bank2: u8, // 2-bit upper ROM / RAM bank register, 0x4000-0x5FFF
+	pub mode: bool, // Banking mode select, 0x6000-0x7FFF
+	pub multicart: bool, // MBC1M wires only 4 bits of bank1
+}
+
+pub struct Mbc3 {
+	pub ram_enabled: bool, // Also gates the RTC registers
+	pub rom_bank: u8, // 7-bit, 0x2000-0x3FFF
+	pub ram_select: u8, // RAM bank 0x00-0x03 or RTC register 0x08-0x0C, 0x4000-0x5FFF
+	pub rtc: Option<Rtc>,
+}
+
+pub struct Mbc2 {
+	pub ram_enabled: bool,
+	pub rom_bank: u8, // 4-bit
+}
+
+pub struct Mbc5 {
+	pub ram_enabled: bool,
+	pub rom_bank: u16, // 9-bit, low byte at 0x2000-0x2FFF and bit 8 at 0x3000-0x3FFF
+	pub ram_bank: u8, // 4-bit, or 3-bit on rumble carts
+	pub has_rumble: bool,
+	pub rumble: bool, // Motor state, for frontends that can shake
+}
+
+#[allow(clippy::enum_variant_names)]
+pub enum Mbc {
+	NoMbc,
+	Mbc1(Mbc1),
+	Mbc2(Mbc2),
+	Mbc3(Mbc3),
+	Mbc5(Mbc5),
+}
+
+// MBC2 has 512 half-bytes built in rather than a separate RAM chip
+pub const MBC2_RAM_SIZE: usize = 0x200;
+
+// Byte offset of loc within the given bank, wrapping the bank to what the chip has
+fn banked(len:usize, bank_size:usize, bank:usize, loc:u16) -> usize {
+	let banks = (len / bank_size).max(1);
+	(bank % banks) * bank_size + (loc as usize & (bank_size - 1))
+}
+
+// MBC1M carts repeat the Nintendo logo at the start of each 256 KiB game
+fn is_multicart(rom:&[u8]) -> bool {
+	let logo = 0x0104 .. 0x0134;
+	let second = 0x10 * ROM_BANK_SIZE + 0x0104;
+	rom.len() == 0x100000 && rom[logo.clone()] == rom[second .. second + logo.len()]
+}
+
+impl Mbc1 {
+	pub fn create(multicart:bool) -> Mbc1 {
+		Mbc1 {
+			ram_enabled: false,
+			bank1: 1,
+			bank2: 0,
+			mode: false,
+			multicart
+		}
+	}
+
+	fn upper_shift(&self) -> u8 {
+		if self.multicart { 4 } else { 5 }
+	}
+
+	fn rom_bank(&self, loc:u16) -> usize {
+		let upper = (self.bank2 << self.upper_shift()) as usize;
+		if loc < 0x4000 {
+			if self.mode { upper } else { 0 }
+		} else {
+			let mask = if self.multicart { 0x0F } else { 0x1F };
+			upper | (self.bank1 & mask) as usize
+		}
+	}
+
+	fn ram_bank(&self) -> usize {
+		if self.mode { self.bank2 as usize } else { 0 }
+	}
+
+	fn write(&mut self, loc:u16, val:u8) {
+		match loc {
+			0x0000 ..= 0x1FFF => self.ram_enabled = val & 0x0F == 0x0A,
+			0x2000 ..= 0x3FFF => {
+				// Zero is checked on the full 5 bits, so 0x20 still maps to bank 0x21
+				self.bank1 = val & 0x1F;
+				if self.bank1 == 0 {
+					self.bank1 = 1;
+				}
+			}
+			0x4000 ..= 0x5FFF => self.bank2 = val & 0x03,
+			_ => self.mode = val & 0x01 == 0x01,
+		}
+	}
+}
+
+impl Mbc3 {
+	pub fn create(has_rtc:bool) -> Mbc3 {
+		Mbc3 {
+			ram_enabled: false,
+			rom_bank: 1,
+			ram_select: 0,
+			rtc: if has_rtc { Some(Rtc::create()) } else { None }
+		}
+	}
+
+	fn rtc_selected(&self) -> bool {
+		(0x08 ..= 0x0C).contains(&self.ram_select)
+	}
+
+	fn write(&mut self, loc:u16, val:u8) {
+		match loc {
+			0x0000 ..= 0x1FFF => self.ram_enabled = val & 0x0F == 0x0A,
+			0x2000 ..= 0x3FFF => {
+				self.rom_bank = val & 0x7F;
+				if self.rom_bank == 0 {
+					self.rom_bank = 1;
+				}
+			}
+			0x4000 ..= 0x5FFF => self.ram_select = val & 0x0F,
+			_ => {
+				if let Some(ref mut rtc) = self.rtc {
+					rtc.write_latch(val);
+				}
+			}
+		}
+	}
+}
+
+impl Mbc2 {
+	pub fn create() -> Mbc2 {
+		Mbc2 {
+			ram_enabled: false,
+			rom_bank: 1
+		}
+	}
+
+	fn write(&mut self, loc:u16, val:u8) {
+		// Address bit 8 picks the register, and only the lower half of the space decodes it
+		if loc >= 0x4000 {
+			return;
+		}
+		if loc & 0x0100 == 0 {
+			self.ram_enabled = val & 0x0F == 0x0A;
+		} else {
+			self.rom_bank = val & 0x0F;
+			if self.rom_bank == 0 {
+				self.rom_bank = 1;
+			}
+		}
+	}
+}
+
+impl Mbc5 {
+	pub fn create(has_rumble:bool) -> Mbc5 {
+		Mbc5 {
+			ram_enabled: false,
+			rom_bank: 1,
+			ram_bank: 0,
+			has_rumble,
+			rumble: false
+		}
+	}
+
+	fn write(&mut self, loc:u16, val:u8) {
+		match loc {
+			0x0000 ..= 0x1FFF => self.ram_enabled = val & 0x0F == 0x0A,
+			0x2000 ..= 0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | val as u16,
+			0x3000 ..= 0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((val as u16 & 0x01) << 8),
+			0x4000 ..= 0x5FFF => {
+				if self.has_rumble {
+					self.rumble = val & 0x08 != 0;
+					self.ram_bank = val & 0x07;
+				} else {
+					self.ram_bank = val & 0x0F;
+				}
+			}
+			_ => {}
+		}
+	}
+}
+
+impl Mbc {
+	// Picks the controller named by the cartridge type byte at 0x0147
+	pub fn from_type(cart_type:u8, rom:&[u8]) -> Mbc {
+		match cart_type {
+			0x01 ..= 0x03 => Mbc::Mbc1(Mbc1::create(is_multicart(rom))),
+			0x05 | 0x06 => Mbc::Mbc2(Mbc2::create()),
+			0x0F | 0x10 => Mbc::Mbc3(Mbc3::create(true)),
+			0x11 ..= 0x13 => Mbc::Mbc3(Mbc3::create(false)),
+			0x19 ..= 0x1B => Mbc::Mbc5(Mbc5::create(false)),
+			0x1C ..= 0x1E => Mbc::Mbc5(Mbc5::create(true)),
+			_ => Mbc::NoMbc,
+		}
+	}
+
+	// Points 0x4000-0x7FFF at any bank directly, without the bank 0 quirks, for tools viewing the ROM
+	pub fn map_rom_bank(&mut self, bank:usize) {
+		match *self {
+			Mbc::NoMbc => {},
+			Mbc::Mbc1(ref mut mbc) => {
+				let shift = mbc.upper_shift();
+				mbc.bank1 = (bank & ((1 << shift) - 1)) as u8;
+				mbc.bank2 = (bank >> shift) as u8 & 0x03;
+			}
+			Mbc::Mbc2(ref mut mbc) => mbc.rom_bank = bank as u8 & 0x0F,
+			Mbc::Mbc3(ref mut mbc) => mbc.rom_bank = bank as u8 & 0x7F,
+			Mbc::Mbc5(ref mut mbc) => mbc.rom_bank = bank as u16 & 0x1FF,
+		}
+	}
+
+	// Which ROM or RAM bank the CPU currently sees at loc, 0 outside the cartridge
+	pub fn bank_at(&self, loc:u16) -> usize {
+		match loc {
+			0x0000 ..= 0x7FFF => match *self {
+				Mbc::Mbc1(ref mbc) => mbc.rom_bank(loc),
+				_ if loc < 0x4000 => 0,
+				Mbc::NoMbc => 1,
+				Mbc::Mbc2(ref mbc) => mbc.rom_bank as usize,
+				Mbc::Mbc3(ref mbc) => mbc.rom_bank as usize,
+				Mbc::Mbc5(ref mbc) => mbc.rom_bank as usize,
+			},
+			0xA000 ..= 0xBFFF => match *self {
+				Mbc::Mbc1(ref mbc) => mbc.ram_bank(),
+				Mbc::Mbc3(ref mbc) => (mbc.ram_select & 0x03) as usize,
+				Mbc::Mbc5(ref mbc) => mbc.ram_bank as usize,
+				_ => 0,
+			},
+			_ => 0,
+		}
+	}
+
+	pub fn read_rom(&self, rom:&[u8], loc:u16) -> u8 {
+		let offset = match *self {
+			Mbc::NoMbc => loc as usize,
+			Mbc::Mbc1(ref mbc) => banked(rom.len(), ROM_BANK_SIZE, mbc.rom_bank(loc), loc),
+			Mbc::Mbc2(ref mbc) => {
+				let bank = if loc < 0x4000 { 0 } else { mbc.rom_bank as usize };
+				banked(rom.len(), ROM_BANK_SIZE, bank, loc)
+			}
+			Mbc::Mbc3(ref mbc) => {
+				let bank = if loc < 0x4000 { 0 } else { mbc.rom_bank as usize };
+				banked(rom.len(), ROM_BANK_SIZE, bank, loc)
+			}
+			Mbc::Mbc5(ref mbc) => {
+				let bank = if loc < 0x4000 { 0 } else { mbc.rom_bank as usize };
+				banked(rom.len(), ROM_BANK_SIZE, bank, loc)
+			}
+		};
+		rom.get(offset).cloned().unwrap_or(0xFF)
+	}
+
+	pub fn write_rom(&mut self, loc:u16, val:u8) {
+		match *self {
+			Mbc::NoMbc => {},
+			Mbc::Mbc1(ref mut mbc) => mbc.write(loc, val),
+			Mbc::Mbc2(ref mut mbc) => mbc.write(loc, val),
+			Mbc::Mbc3(ref mut mbc) => mbc.write(loc, val),
+			Mbc::Mbc5(ref mut mbc) => mbc.write(loc, val),
+		}
+	}
+
+	pub fn tick(&mut self, cycles:u64) {
+		if let Mbc::Mbc3(Mbc3 { rtc: Some(ref mut rtc), .. }) = *self {
+			rtc.tick(cycles);
+		}
+	}
+
+	pub fn rtc(&mut self) -> Option<&mut Rtc> {
+		match *self {
+			Mbc::Mbc3(ref mut mbc) => mbc.rtc.as_mut(),
+			_ => None,
+		}
+	}
+
+	fn ram_offset(&self, ram:&[u8], loc:u16) -> Option<usize> {
+		if ram.is_empty() {
+			return None;
+		}
+		match *self {
+			Mbc::NoMbc => Some(banked(ram.len(), RAM_BANK_SIZE, 0, loc) % ram.len()),
+			Mbc::Mbc1(ref mbc) => {
+				if !mbc.ram_enabled {
+					return None;
+				}
+				Some(banked(ram.len(), RAM_BANK_SIZE, mbc.ram_bank(), loc) % ram.len())
+			}
+			Mbc::Mbc2(ref mbc) => {
+				if !mbc.ram_enabled {
+					return None;
+				}
+				// The 512 entries echo through the whole 0xA000-0xBFFF window
+				Some(loc as usize % ram.len())
+			}
+			Mbc::Mbc3(ref mbc) => {
+				if !mbc.ram_enabled || mbc.ram_select > 0x03 {
+					return None;
+				}
+				Some(banked(ram.len(), RAM_BANK_SIZE, mbc.ram_select as usize, loc) % ram.len())
+			}
+			Mbc::Mbc5(ref mbc) => {
+				if !mbc.ram_enabled {
+					return None;
+				}
+				Some(banked(ram.len(), RAM_BANK_SIZE, mbc.ram_bank as usize, loc) % ram.len())
+			}
+		}
+	}
+
+	pub fn read_ram(&self, ram:&[u8], loc:u16) -> u8 {
+		if let Mbc::Mbc3(ref mbc) = *self {
+			if let Some(ref rtc) = mbc.rtc {
+				if mbc.ram_enabled && mbc.rtc_selected() {
+					return rtc.read(mbc.ram_select);
+				}
+			}
+		}
+		match self.ram_offset(ram, loc) {
+			// MBC2 RAM is 4 bits wide, the upper half of the bus floats high
+			Some(offset) if self.is_mbc2() => ram[offset] | 0xF0,
+			Some(offset) => ram[offset],
+			None => 0xFF,
+		}
+	}
+
+	pub fn is_mbc2(&self) -> bool {
+		matches!(*self, Mbc::Mbc2(_))
+	}
+
+	// Whether the write reached RAM, rather than being dropped or going to the RTC
+	pub fn write_ram(&mut self, ram:&mut [u8], loc:u16, val:u8) -> bool {
+		if let Mbc::Mbc3(ref mut mbc) = *self {
+			let selected = mbc.ram_enabled && mbc.rtc_selected();
+			if let Some(ref mut rtc) = mbc.rtc {
+				if selected {
+					rtc.write(mbc.ram_select, val);
+					return false;
+				}
+			}
+		}
+		match self.ram_offset(ram, loc) {
+			Some(offset) => {
+				ram[offset] = if self.is_mbc2() { val & 0x0F } else { val };
+				true
+			}
+			None => false,
+		}
+	}
+}
+
+mod test {
+	// A ROM where every byte holds the number of the bank it's in
+	#[cfg(test)]
+	fn numbered_rom(banks:usize) -> Vec<u8> {
+		let mut rom = vec![0; banks * 0x4000];
+		for (i, byte) in rom.iter_mut().enumerate() {
+			*byte = (i / 0x4000) as u8;
+		}
+		rom
+	}
+
+	#[test]
+	fn test_no_mbc() {
+		use super::*;
+		let rom = numbered_rom(2);
+		let mut mbc = Mbc::from_type(0x00, &rom);
+		mbc.write_rom(0x2000, 0x05);
+		assert_eq!(mbc.read_rom(&rom, 0x0000), 0);
+		assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+	}
+
+	#[test]
+	fn test_mbc1_rom_banks() {
+		use super::*;
+		let rom = numbered_rom(128);
+		let mut mbc = Mbc::from_type(0x01, &rom);
+		assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+		mbc.write_rom(0x2000, 0x05);
+		assert_eq!(mbc.read_rom(&rom, 0x7FFF), 5);
+		// Bank 0 selects bank 1
+		mbc.write_rom(0x2000, 0x00);
+		assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+		// Upper bits come from the second register
+		mbc.write_rom(0x4000, 0x02);
+		mbc.write_rom(0x2000, 0x03);
+		assert_eq!(mbc.read_rom(&rom, 0x4000), 0x43);
+		assert_eq!(mbc.read_rom(&rom, 0x0000), 0);
+		// Only the low 5 bits are checked for zero, so 0x40 reads as 0x41
+		mbc.write_rom(0x2000, 0x20);
+		assert_eq!(mbc.read_rom(&rom, 0x4000), 0x41);
+	}
+
+	#[test]
+	fn test_mbc1_mode() {
+		use super::*;
+		let rom = numbered_rom(128);
+		let mut mbc = Mbc::from_type(0x01, &rom);
+		mbc.write_rom(0x4000, 0x01);
+		assert_eq!(mbc.read_rom(&rom, 0x0000), 0);
+		mbc.write_rom(0x6000, 0x01);
+		assert_eq!(mbc.read_rom(&rom, 0x0000), 0x20);
+		assert_eq!(mbc.read_rom(&rom, 0x4000), 0x21);
+	}
+
+	#[test]
+	fn test_mbc1_bank_wrap() {
+		use super::*;
+		let rom = numbered_rom(8);
+		let mut mbc = Mbc::from_type(0x01, &rom);
+		mbc.write_rom(0x2000, 0x0A);
+		assert_eq!(mbc.read_rom(&rom, 0x4000), 2);
+	}
+
+	#[test]
+	fn test_mbc1_ram() {
+		use super::*;
+		let rom = numbered_rom(4);
+		let mut ram = vec![0; 0x8000];
+		let mut mbc = Mbc::from_type(0x03, &rom);
+		assert!(!mbc.write_ram(&mut ram, 0xA000, 0x11));
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 0xFF);
+		mbc.write_rom(0x0000, 0x0A);
+		assert!(mbc.write_ram(&mut ram, 0xA000, 0x11));
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 0x11);
+		// RAM banking only applies in mode 1
+		mbc.write_rom(0x4000, 0x02);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 0x11);
+		mbc.write_rom(0x6000, 0x01);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 0x00);
+		mbc.write_ram(&mut ram, 0xBFFF, 0x22);
+		assert_eq!(ram[0x5FFF], 0x22);
+		mbc.write_rom(0x0000, 0x00);
+		assert_eq!(mbc.read_ram(&ram, 0xBFFF), 0xFF);
+	}
+
+	#[test]
+	fn test_mbc1_multicart() {
+		use super::*;
+		let mut rom = numbered_rom(64);
+		for i in 0 .. 0x30 {
+			rom[0x0104 + i] = i as u8;
+			rom[0x40104 + i] = i as u8;
+		}
+		let mut mbc = Mbc::from_type(0x01, &rom);
+		mbc.write_rom(0x4000, 0x01);
+		mbc.write_rom(0x2000, 0x12);
+		assert_eq!(mbc.read_rom(&rom, 0x4000), 0x12);
+		mbc.write_rom(0x6000, 0x01);
+		assert_eq!(mbc.read_rom(&rom, 0x0100), 0x10);
+	}
+
+	#[test]
+	fn test_mbc3_rom_ram() {
+		use super::*;
+		let rom = numbered_rom(128);
+		let mut ram = vec![0; 0x8000];
+		let mut mbc = Mbc::from_type(0x13, &rom);
+		assert!(mbc.rtc().is_none());
+		mbc.write_rom(0x2000, 0x7F);
+		assert_eq!(mbc.read_rom(&rom, 0x4000), 0x7F);
+		mbc.write_rom(0x2000, 0x00);
+		assert_eq!(mbc.read_rom(&rom, 0x4000), 0x01);
+		mbc.write_rom(0x0000, 0x0A);
+		mbc.write_rom(0x4000, 0x03);
+		mbc.write_ram(&mut ram, 0xA000, 0x5A);
+		assert_eq!(ram[0x6000], 0x5A);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 0x5A);
+		// No RTC on this cart, so its registers read as open bus
+		mbc.write_rom(0x4000, 0x08);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 0xFF);
+	}
+
+	#[test]
+	fn test_mbc3_rtc() {
+		use super::*;
+		use super::super::rtc::{ClockSource, CYCLES_PER_SECOND};
+		let rom = numbered_rom(4);
+		let mut ram = vec![0; 0x2000];
+		let mut mbc = Mbc::from_type(0x10, &rom);
+		mbc.rtc().unwrap().set_source(ClockSource::Cycles);
+		mbc.write_rom(0x0000, 0x0A);
+		mbc.write_rom(0x4000, 0x09);
+		assert!(!mbc.write_ram(&mut ram, 0xA000, 30));
+		assert!(mbc.rtc().unwrap().dirty);
+		mbc.tick(CYCLES_PER_SECOND * 90);
+		mbc.write_rom(0x6000, 0x00);
+		mbc.write_rom(0x6000, 0x01);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 31);
+		mbc.write_rom(0x4000, 0x08);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 30);
+		// RAM bank 0 is untouched by the register writes
+		mbc.write_rom(0x4000, 0x00);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 0x00);
+	}
+
+	#[test]
+	fn test_mbc5_rom_banks() {
+		use super::*;
+		let mut rom = numbered_rom(512);
+		// Bank numbers above 0xFF don't fit in a byte, so mark bank 0x1AB separately
+		rom[0x1AB * 0x4000] = 0xAB;
+		rom[0x1AB * 0x4000 + 1] = 0x01;
+		let mut mbc = Mbc::from_type(0x19, &rom);
+		assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+		mbc.write_rom(0x2000, 0x00);
+		assert_eq!(mbc.read_rom(&rom, 0x4000), 0);
+		mbc.write_rom(0x2000, 0xAB);
+		mbc.write_rom(0x3000, 0x01);
+		assert_eq!(mbc.read_rom(&rom, 0x4000), 0xAB);
+		assert_eq!(mbc.read_rom(&rom, 0x4001), 0x01);
+		assert_eq!(mbc.read_rom(&rom, 0x0000), 0);
+	}
+
+	#[test]
+	fn test_mbc5_ram() {
+		use super::*;
+		let rom = numbered_rom(4);
+		let mut ram = vec![0; 0x20000];
+		let mut mbc = Mbc::from_type(0x1B, &rom);
+		mbc.write_rom(0x0000, 0x0A);
+		mbc.write_rom(0x4000, 0x0F);
+		mbc.write_ram(&mut ram, 0xA000, 0x77);
+		assert_eq!(ram[0x1E000], 0x77);
+		assert_eq!(mbc.read_ram(&ram, 0xA000), 0x77);
+	}
+
+	#[test]
+	fn test_mbc5_rumble() {
+		use super::*;
+		let rom = numbered_rom(4);
+		let mut ram = vec![0; 0x8000];
+		let mut mbc = Mbc::from_type(0x1E, &rom);
+		mbc.write_rom(0x0000, 0x0A);
+		mbc.write_rom(0x4000, 0x09);
+		mbc.write_ram(&mut ram, 0xA000, 0x33);
+		assert_eq!(ram[0x2000], 0x33);
+		match mbc {
+			Mbc::Mbc5(ref mbc5) => assert!(mbc5.rumble),
+			_ => panic!("Expected MBC5"),
+		}
+	}
+
+	#[test]
+	fn test_mbc2_registers() {
+		use super::*;
+		let rom = numbered_rom(16);
+		let mut ram = vec![0; MBC2_RAM_SIZE];
+		let mut mbc = Mbc::from_type(0x06, &rom);
+		// Bit 8 set selects the ROM bank register
+		mbc.write_rom(0x2100, 0x0B);
+		assert_eq!(mbc.read_rom(&rom, 0x4000), 0x0B);
+		mbc.write_rom(0x0100, 0x00);
+		assert_eq!(mbc.read_rom(&rom, 0x4000), 0x01);
+		// Bit 8 clear is RAM enable, anywhere in 0x0000-0x3FFF
+		mbc.write_rom(0x3000, 0x0A);
+		mbc.write_ram(&mut ram, 0xA005, 0xAB);
+		assert_eq!(ram[5], 0x0B);
+		assert_eq!(mbc.read_ram(&ram, 0xA005), 0xFB);
+		assert_eq!(mbc.read_ram(&ram, 0xA205), 0xFB);
+		assert_eq!(mbc.read_ram(&ram, 0xBE05), 0xFB);
+		mbc.write_rom(0x0000, 0x00);
+		assert_eq!(mbc.read_ram(&ram, 0xA005), 0xFF);
+	}
+
+	#[test]
+	fn test_map_rom_bank() {
+		use super::*;
+		let rom = numbered_rom(64);
+		let mut mbc = Mbc::from_type(0x01, &rom);
+		// Bank 0x20 can't be reached through the registers, 0x00 in bank1 turns into 0x01
+		mbc.map_rom_bank(0x20);
+		assert_eq!(mbc.read_rom(&rom, 0x4000), 0x20);
+		let mut mbc = Mbc::from_type(0x19, &rom);
+		mbc.map_rom_bank(0x2A);
+		assert_eq!(mbc.read_rom(&rom, 0x4000), 0x2A);
+	}
+
+	#[test]
+	fn test_bank_at() {
+		use super::*;
+		let rom = numbered_rom(64);
+		let mut mbc = Mbc::from_type(0x13, &rom);
+		assert_eq!(mbc.bank_at(0x0100), 0);
+		assert_eq!(mbc.bank_at(0x4000), 1);
+		mbc.write_rom(0x2000, 0x05);
+		mbc.write_rom(0x4000, 0x02);
+		assert_eq!(mbc.bank_at(0x7FFF), 5);
+		assert_eq!(mbc.bank_at(0xA000), 2);
+		assert_eq!(Mbc::NoMbc.bank_at(0x4000), 1);
+	}
+}
@@ -1,67 +1,180 @@
+use std::cell::Cell;
+
 use super::rom::ROM;
 use super::display::Display;
-use super::Core;
 
 use super::timer::Timer;
+use super::interrupts::Interrupts;
+use super::joypad::Joypad;
+use super::watch;
+use super::watch::{Watchpoint, WatchHit, WatchKind};
 
 pub struct Memory {
 	pub rom: ROM,
 	pub ram: [u8; 0x207F], // 0x2000 bytes for 0xC000-0xE000, 0x7f for 0xFF80-0xFF
 	pub disp: Display,
 	pub timer: Timer,
+	pub int: Interrupts,
+	pub joypad: Joypad,
+	pub double_speed: bool, // CGB KEY1 bit 7
+	pub speed_armed: bool, // CGB KEY1 bit 0, STOP switches speed when set
+	pub dma_source: u8, // 0xFF46, high byte of the OAM DMA source
+	pub dma_active: bool,
+	pub dma_index: u16, // Next OAM byte the running DMA will copy
+	pub watchpoints: Vec<Watchpoint>,
+	pub watch_pc: Option<u16>, // Set by the CPU while an instruction runs and watchpoints are armed
+	pub watch_hit: Cell<Option<WatchHit>>, // First watchpoint tripped by that instruction
 }
 
 impl Memory {
 	pub fn create_memory() -> Memory {
-		return Memory{
+		Memory {
 			rom: ROM::create_rom(),
 			ram: [0; 0x207F],
 			disp: Display::create(),
-			timer: Timer::create()
+			timer: Timer::create(),
+			int: Interrupts::create(),
+			joypad: Joypad::create(),
+			double_speed: false,
+			speed_armed: false,
+			dma_source: 0xFF,
+			dma_active: false,
+			dma_index: 0,
+			watchpoints: Vec::new(),
+			watch_pc: None,
+			watch_hit: Cell::new(None)
 		}
 	}
 
 	pub fn update(&mut self, steps:u64) {
-		self.disp.update(steps);
-		self.timer.step(steps);
+		if self.dma_active {
+			self.step_dma(steps / 4);
+		}
+		// The PPU runs off the undivided clock, so it sees half the cycles in double speed
+		let disp_steps = if self.double_speed { steps / 2 } else { steps };
+		self.disp.update(disp_steps, &mut self.int);
+		self.rom.update(disp_steps);
+		self.timer.step(steps, &mut self.int);
+	}
+
+	pub fn switch_speed(&mut self) {
+		self.double_speed = !self.double_speed;
+		self.speed_armed = false;
+	}
+
+	fn start_dma(&mut self, source:u8) {
+		self.dma_source = source;
+		self.dma_active = true;
+		self.dma_index = 0;
+	}
+
+	// Copies one byte per M-cycle, straight into OAM regardless of PPU mode
+	fn step_dma(&mut self, m_cycles:u64) {
+		for _ in 0 .. m_cycles {
+			let src = ((self.dma_source as u16) << 8) | self.dma_index;
+			self.disp.oam[self.dma_index as usize] = self.read_bus(src);
+			self.dma_index += 1;
+			if self.dma_index == 0xA0 {
+				self.dma_active = false;
+				return;
+			}
+		}
+	}
+
+	// While DMA owns the bus the CPU can only reach IO, HRAM and IE, so it can still restart the transfer
+	fn dma_blocks(&self, loc:u16) -> bool {
+		self.dma_active && loc < 0xFF00
 	}
 
 	pub fn get_mem(&self, loc:u16) -> u8 {
-		//println!("Read {:2X}", loc);
+		let val = self.fetch(loc);
+		if let Some(pc) = self.watch_pc {
+			self.record_watch(watch::check(&self.watchpoints, WatchKind::Read, loc, pc, val, val));
+		}
+		val
+	}
+
+	// Opcode and operand reads, which skip watchpoints so a read watch over code doesn't trip on execution
+	pub fn fetch(&self, loc:u16) -> u8 {
+		if self.dma_blocks(loc) {
+			log!(Mem, Debug, "Read from {:04X} blocked by OAM DMA", loc);
+		}
+		let val = self.peek(loc);
+		log!(Mem, Trace, "Read {:02X} from {:04X}", val, loc);
+		val
+	}
+
+	// What the CPU would read, without logging or tripping watchpoints
+	pub fn peek(&self, loc:u16) -> u8 {
+		if self.dma_blocks(loc) {
+			return 0xFF;
+		}
+		self.read_bus(loc)
+	}
+
+	// The first watchpoint the last instruction tripped, clearing it for the next one
+	pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+		self.watch_hit.take()
+	}
+
+	fn record_watch(&self, hit:Option<WatchHit>) {
+		if hit.is_some() && self.watch_hit.get().is_none() {
+			self.watch_hit.set(hit);
+		}
+	}
+
+	fn read_bus(&self, loc:u16) -> u8 {
 		match loc {
 			0x0000 ..= 0x7FFF => self.rom.get_mem(loc),
-			0x8000 ..= 0x9FFF => 0, // VRAM
-			0xA000 ..= 0xBFFF => 0, // SWITCH_RAM
+			0x8000 ..= 0x9FFF => self.disp.get_mem(loc), // VRAM
+			0xA000 ..= 0xBFFF => self.rom.get_ram(loc), // SWITCH_RAM
 			0xC000 ..= 0xDFFF => self.ram[(loc - 0xC000) as usize], // RAM
 			0xE000 ..= 0xFDFF => self.ram[(loc - 0xE000) as usize], // RAM echo
-			0xFE00 ..= 0xFE9F => 0, // OAM
+			0xFE00 ..= 0xFE9F => self.disp.get_mem(loc), // OAM
 			0xFEA0 ..= 0xFEFF => 0, // IO
-			0xFF00 => 0, // Gamepad
-			0xFF01 ..= 0xFF3F => 0, // IO
+			0xFF00 => self.joypad.get_mem(), // Gamepad
+			0xFF01 ..= 0xFF03 => 0, // IO
+			0xFF04 ..= 0xFF07 => self.timer.get_mem(loc),
+			0xFF08 ..= 0xFF0E => 0, // IO
+			0xFF0F => self.int.get_mem(loc),
+			0xFF10 ..= 0xFF3F => 0, // IO
+			0xFF46 => self.dma_source,
 			0xFF40 ..= 0xFF4B => self.disp.get_mem(loc),
+			0xFF4D => ((self.double_speed as u8) << 7) | 0x7E | (self.speed_armed as u8),
 			0xFF4C ..= 0xFF7F => 0, // IO
 			0xFF80 ..= 0xFFFE => self.ram[(0x2000 + (loc - 0xFF80)) as usize],// RAM
-			0xFFFF => 0,
-			_ => {
-				// It is terrible that I need this at all
-				panic!("Read out of bounds at {:2X}", loc);
-				0
-			}
+			0xFFFF => self.int.get_mem(loc),
 		}
 	}
 
 	pub fn set_mem(&mut self, loc:u16, val:u8) {
-		//println!("Wrote {:2X} to {:2X}", val, loc);
+		log!(Mem, Trace, "Wrote {:02X} to {:04X}", val, loc);
+		if let Some(pc) = self.watch_pc {
+			let old = self.peek(loc);
+			self.store(loc, val);
+			// Read back what landed, since MBC registers, locked VRAM and masked IO don't keep val
+			let new = self.peek(loc);
+			self.record_watch(watch::check(&self.watchpoints, WatchKind::Write, loc, pc, old, new));
+		} else {
+			self.store(loc, val);
+		}
+	}
+
+	fn store(&mut self, loc:u16, val:u8) {
+		if self.dma_blocks(loc) {
+			log!(Mem, Debug, "Write to {:04X} blocked by OAM DMA", loc);
+			return;
+		}
 
 		match loc {
 			0x0000 ..= 0x7FFF => {
-				// CART
+				self.rom.set_mem(loc, val); // CART
 			},
 			0x8000 ..= 0x9FFF => {
-				self.disp.set_mem(loc, val);
+				self.disp.set_mem(loc, val, &mut self.int); // VRAM
 			},
 			0xA000 ..= 0xBFFF => {
-				// SWITCH_RAM
+				self.rom.set_ram(loc, val); // SWITCH_RAM
 			},
 			0xC000 ..= 0xDFFF => {
 				self.ram[(loc - 0xC000) as usize] = val; // RAM
@@ -70,14 +183,32 @@ impl Memory {
 				self.ram[(loc - 0xE000) as usize] = val; // RAM echo
 			},
 			0xFE00 ..= 0xFE9F => {
-				// OAM
+				self.disp.set_mem(loc, val, &mut self.int); // OAM
 			},
 			0xFEA0 ..= 0xFEFF => {
 				// IO
 			},
-			0xFF00 ..= 0xFF4B => {
+			0xFF00 => {
+				self.joypad.set_mem(val, &mut self.int); // Gamepad
+			},
+			0xFF04 ..= 0xFF07 => {
+				self.timer.set_mem(loc, val);
+			},
+			0xFF0F => {
+				self.int.set_mem(loc, val);
+			},
+			0xFF01 ..= 0xFF3F => {
 				// IO
 			},
+			0xFF46 => {
+				self.start_dma(val);
+			},
+			0xFF40 ..= 0xFF4B => {
+				self.disp.set_mem(loc, val, &mut self.int);
+			},
+			0xFF4D => {
+				self.speed_armed = val & 0x01 == 0x01;
+			},
 			0xFF4C ..= 0xFF7F => {
 				// IO
 			},
@@ -85,12 +216,8 @@ impl Memory {
 				self.ram[(0x2000 + (loc - 0xFF80)) as usize] = val; // RAM
 			},
 			0xFFFF => {
-				// INT
+				self.int.set_mem(loc, val);
 			},
-			_ => {
-				// It is terrible that I need this at all
-				panic!("Write out of bounds at {:2X}", loc);
-			}
 		}
 	}
 }
@@ -144,4 +271,73 @@ mod test {
 		assert_eq!(memory.get_mem(0xCBBF), 0xC3);
 		assert_eq!(memory.get_mem(0xEBBF), 0xC3);
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn test_dma() {
+		let mut memory = super::Memory::create_memory();
+		for i in 0 .. 0xA0 {
+			memory.set_mem(0xC100 + i, i as u8);
+		}
+		memory.set_mem(0xFF46, 0xC1);
+		assert!(memory.dma_active);
+		memory.update(4 * 0x50);
+		assert!(memory.dma_active);
+		assert_eq!(memory.disp.oam[0x4F], 0x4F);
+		assert_eq!(memory.disp.oam[0x50], 0x00);
+		memory.update(4 * 0x50);
+		assert!(!memory.dma_active);
+		for i in 0 .. 0xA0 {
+			assert_eq!(memory.disp.oam[i], i as u8);
+		}
+		assert_eq!(memory.get_mem(0xFF46), 0xC1);
+	}
+
+	#[test]
+	fn test_dma_bus_restriction() {
+		let mut memory = super::Memory::create_memory();
+		memory.set_mem(0xC000, 0x12);
+		memory.set_mem(0xFF46, 0xC0);
+		assert_eq!(memory.get_mem(0xC000), 0xFF);
+		memory.set_mem(0xC000, 0x34);
+		memory.set_mem(0xFF80, 0x56);
+		assert_eq!(memory.get_mem(0xFF80), 0x56);
+		memory.update(4 * 0xA0);
+		assert_eq!(memory.get_mem(0xC000), 0x12);
+	}
+
+	#[test]
+	fn test_change_watch_ignored_write() {
+		use super::super::watch::{Watchpoint, WatchKind};
+		let mut memory = super::Memory::create_memory();
+		memory.watchpoints.push(Watchpoint { start: 0x2000, end: 0x2000, kind: WatchKind::Change });
+		memory.watchpoints.push(Watchpoint { start: 0xC000, end: 0xC000, kind: WatchKind::Change });
+		memory.watch_pc = Some(0x0150);
+		// An MBC register write leaves ROM as it was
+		memory.set_mem(0x2000, 0x05);
+		assert_eq!(memory.watch_hit.get(), None);
+		// So does a write the DMA blocks
+		memory.set_mem(0xFF46, 0xC1);
+		memory.set_mem(0xC000, 0x12);
+		assert_eq!(memory.watch_hit.get(), None);
+		memory.update(4 * 0xA0);
+		memory.set_mem(0xC000, 0x12);
+		assert_eq!(memory.watch_hit.get().unwrap().new, 0x12);
+	}
+
+	#[test]
+	fn test_dma_io_access() {
+		let mut memory = super::Memory::create_memory();
+		memory.set_mem(0xC100, 0x77);
+		memory.set_mem(0xFF46, 0xC0);
+		memory.update(4 * 0x10);
+		memory.set_mem(0xFFFF, 0x1F);
+		assert_eq!(memory.get_mem(0xFFFF), 0x1F);
+		// Restarting copies from the new source, beginning again at the first OAM byte
+		memory.set_mem(0xFF46, 0xC1);
+		assert_eq!(memory.dma_index, 0);
+		assert_eq!(memory.get_mem(0xFF46), 0xC1);
+		memory.update(4 * 0xA0);
+		assert!(!memory.dma_active);
+		assert_eq!(memory.disp.oam[0], 0x77);
+	}
+}
@@ -1,20 +1,49 @@
+#[macro_use]
+pub mod log;
 mod registers;
 mod memory;
 mod rom;
+pub mod error;
+mod header;
+mod mbc;
+mod rtc;
 mod interrupts;
-mod display;
+pub mod display;
 mod timer;
+pub mod joypad;
+mod trace;
+pub mod disasm;
+pub mod debugger;
+mod watch;
+pub mod gdb;
+pub mod symbols;
+
+use std::io::Write;
+
+use self::error::EmuError;
+use self::registers::RegisterName;
+use self::interrupts::Interrupt;
+use self::joypad::Button;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunState {
+	Running,
+	Halted, // Waiting for any enabled interrupt, peripherals keep running
+	Stopped, // Everything is clocked off until a joypad line goes low
+	Locked { opcode: u8, pc: u16 }, // Hit an undefined opcode, only a reset recovers
+}
 
 pub struct Core {
 	pub reg: registers::Registers,
 	pub mem: memory::Memory,
-	pub int: interrupts::Interrupts,
+	pub state: RunState,
+	pub halt_bug: bool,
+	pub trace: Option<trace::Trace>, // Gets a line before every instruction when set
+	pub symbols: symbols::Symbols, // Labels for debug output, empty unless a .sym file was loaded
 }
 
 fn check_add_half_carry(a:u8, b:u8) -> bool {
-	let lower = a & 0xF;
-	let (_res, carry) = lower.overflowing_add(b & 0xF);
-	carry
+	(a & 0xF) + (b & 0xF) > 0xF
 }
 
 fn check_sub_half_carry(a:u8, b:u8) -> bool {
@@ -24,11 +53,13 @@ fn check_sub_half_carry(a:u8, b:u8) -> bool {
 }
 
 fn check_add_half_carry_16(a:u16, b:u16) -> bool {
-	((a & 0xFFF) + (b & 0xFFF) & 0x1000) == 0x1000
+	((a & 0xFFF) + (b & 0xFFF)) & 0x1000 == 0x1000
 }
 
-fn check_sub_half_carry_16(a:u16, b:u16) -> bool {
-	(((a & 0x0FFF) + 0x1000) - (b & 0x0FFF) & 0x1000) == 0x1000
+impl Default for Core {
+	fn default() -> Core {
+		Core::new()
+	}
 }
 
 impl Core {
@@ -36,13 +67,56 @@ impl Core {
 		Core {
 			reg: registers::Registers::load_defaults(),
 			mem: memory::Memory::create_memory(),
-			int: interrupts::Interrupts::create(),
+			state: RunState::Running,
+			halt_bug: false,
+			trace: None,
+			symbols: symbols::Symbols::create(),
 		}
 	}
 
-	pub fn step(&mut self) {
-		let ins = self.mem.get_mem(self.reg.pc);
-		println!("Running {:2X} at {:2X}", ins, self.reg.pc);
+	pub fn step(&mut self) -> Result<(), EmuError> {
+		match self.state {
+			RunState::Running => {},
+			RunState::Halted => {
+				if self.mem.int.pending() {
+					self.state = RunState::Running;
+				} else {
+					self.mem.update(4);
+					return Ok(());
+				}
+			}
+			RunState::Stopped => {
+				// The joypad request bit is set on any high-to-low input edge, even with IE clear
+				if self.mem.int.iflag & Interrupt::Joypad.bit() != 0 {
+					self.state = RunState::Running;
+				} else {
+					return Ok(());
+				}
+			}
+			RunState::Locked { .. } => {
+				// The CPU is wedged but the PPU and timer are clocked as usual
+				self.mem.update(4);
+				return Ok(());
+			}
+		}
+		if !self.mem.watchpoints.is_empty() {
+			self.mem.watch_pc = Some(self.reg.pc);
+		}
+		if self.service_interrupt() {
+			self.end_watch();
+			return Ok(());
+		}
+		if let Some(ref mut trace) = self.trace {
+			trace.write(&self.reg, &self.mem, &self.symbols)?;
+		}
+		let enable_after = self.mem.int.ei_pending;
+		let ins = self.mem.fetch(self.reg.pc);
+		if self.halt_bug {
+			// PC failed to increment past the opcode, so its byte is read again as the next one
+			self.halt_bug = false;
+			self.reg.pc = self.reg.pc.wrapping_sub(1);
+		}
+		log!(Cpu, Trace, "Running {:02X} at {:04X} {}", ins, self.reg.pc, self.reg);
 		let _numsteps:(u16, u64) = match ins {
 			0x00 => (1, 4),
 			0x01 => {
@@ -58,23 +132,27 @@ impl Core {
 			}
 			0x03 => {
 				let val = self.reg.get_bc();
-				let (res, _carry) = val.overflowing_add(1);
-				self.reg.set_bc(res);
+				self.reg.set_bc(val.wrapping_add(1));
 				(1, 8)
 			}
-			0x04 => self.inc_reg(registers::RegisterName::b),
-			0x05 => self.dec_b(),
+			0x04 => self.inc_reg(RegisterName::b),
+			0x05 => self.dec_reg(RegisterName::b),
 			0x06 => {
 				self.reg.b = self.get_8_pc(1);
 				(2, 8)
 			}
-
+			0x07 => { // RLCA
+				let res = self.rlc(self.reg.a);
+				self.reg.a = res;
+				self.reg.set_z(false);
+				(1, 4)
+			}
 			0x08 => {
 				let addr = self.get_16_pc(1);
 				let loval = (self.reg.sp & 0x00FF) as u8;
 				let hival = ((self.reg.sp & 0xFF00) >> 8) as u8;
 				self.mem.set_mem(addr, loval);
-				self.mem.set_mem(addr + 1, hival);
+				self.mem.set_mem(addr.wrapping_add(1), hival);
 				(3, 20)
 			}
 			0x09 => {
@@ -88,16 +166,30 @@ impl Core {
 			}
 			0x0B => {
 				let val = self.reg.get_bc();
-				self.reg.set_bc(val - 1);
+				self.reg.set_bc(val.wrapping_sub(1));
 				(1, 8)
 			}
-			0x0C => self.inc_reg(registers::RegisterName::c),
-			0x0D => self.dec_c(),
+			0x0C => self.inc_reg(RegisterName::c),
+			0x0D => self.dec_reg(RegisterName::c),
 			0x0E => {
 				self.reg.c = self.get_8_pc(1);
 				(2, 8)
 			}
-
+			0x0F => { // RRCA
+				let res = self.rrc(self.reg.a);
+				self.reg.a = res;
+				self.reg.set_z(false);
+				(1, 4)
+			}
+			0x10 => { // STOP
+				if self.mem.speed_armed {
+					self.mem.switch_speed();
+				} else {
+					self.mem.set_mem(0xFF04, 0); // The divider is held in reset
+					self.state = RunState::Stopped;
+				}
+				(2, 4)
+			}
 			0x11 => {
 				let val = self.get_16_pc(1);
 				self.reg.set_de(val);
@@ -110,25 +202,23 @@ impl Core {
 				(1, 8)
 			}
 			0x13 => {
-				let val = self.reg.get_de() + 1;
-				self.reg.set_de(val);
+				let val = self.reg.get_de();
+				self.reg.set_de(val.wrapping_add(1));
 				(1, 8)
 			}
-			0x14 => self.inc_reg(registers::RegisterName::d),
-			0x15 => self.dec_d(),
+			0x14 => self.inc_reg(RegisterName::d),
+			0x15 => self.dec_reg(RegisterName::d),
 			0x16 => {
 				self.reg.d = self.get_8_pc(1);
 				(2, 8)
 			}
-
-			0x18 => {
-				let raw = self.get_8_pc(1);
-				self.reg.pc += raw as u16;
-				if raw & 0x80 != 0 {
-					self.reg.pc -= 256
-				}
-				(2, 8)
+			0x17 => { // RLA
+				let res = self.rl(self.reg.a);
+				self.reg.a = res;
+				self.reg.set_z(false);
+				(1, 4)
 			}
+			0x18 => self.jr(true),
 			0x19 => {
 				let operand = self.reg.get_de();
 				self.add_hl(operand)
@@ -140,30 +230,24 @@ impl Core {
 			}
 			0x1B => {
 				let val = self.reg.get_de();
-				self.reg.set_de(val - 1);
+				self.reg.set_de(val.wrapping_sub(1));
 				(1, 8)
 			}
-			0x1C => self.inc_reg(registers::RegisterName::e),
-			0x1D => self.dec_e(),
+			0x1C => self.inc_reg(RegisterName::e),
+			0x1D => self.dec_reg(RegisterName::e),
 			0x1E => {
 				self.reg.e = self.get_8_pc(1);
 				(2, 8)
 			}
-			0x1F => {
-				let b0 = self.reg.a & 0x1;
-				let mut res = self.reg.a >> 1;
-				res &= (self.reg.get_c() as u8) << 7;
+			0x1F => { // RRA
+				let res = self.rr(self.reg.a);
 				self.reg.a = res;
-				self.reg.set_flags(res == 0, false, false, b0 == 0x1);
+				self.reg.set_z(false);
 				(1, 4)
 			}
 			0x20 => { // JR NZ
-				if !self.reg.get_z() {
-					let offset = self.get_8_pc(1) as u16;
-					self.reg.pc += offset & 0x7f;
-					self.reg.pc -= 128 * ((offset & 0x80) >> 7)
-				}
-				(2, 8)
+				let cond = !self.reg.get_z();
+				self.jr(cond)
 			}
 			0x21 => {
 				let val = self.get_16_pc(1);
@@ -174,67 +258,55 @@ impl Core {
 				let addr = self.reg.get_hl();
 				let val = self.reg.a;
 				self.mem.set_mem(addr, val);
-				self.reg.set_hl(addr + 1);
+				self.reg.set_hl(addr.wrapping_add(1));
 				(1, 8)
 			}
-
 			0x23 => {
-				let val = self.reg.get_hl() + 1;
-				self.reg.set_hl(val);
+				let val = self.reg.get_hl();
+				self.reg.set_hl(val.wrapping_add(1));
 				(1, 8)
 			}
-			0x24 => self.inc_reg(registers::RegisterName::h),
-			0x25 => self.dec_h(),
+			0x24 => self.inc_reg(RegisterName::h),
+			0x25 => self.dec_reg(RegisterName::h),
 			0x26 => {
 				self.reg.h = self.get_8_pc(1);
 				(2, 8)
 			}
-
+			0x27 => self.daa(),
 			0x28 => { // JR Z
-				if self.reg.get_z() {
-					let offset = self.get_8_pc(1) as u16;
-					self.reg.pc += offset & 0x7f;
-					self.reg.pc -= 128 * ((offset & 0x80) >> 7)
-				}
-				(2, 8)
+				let cond = self.reg.get_z();
+				self.jr(cond)
 			}
 			0x29 => {
 				let operand = self.reg.get_hl();
 				self.add_hl(operand)
 			}
-
 			0x2A => {
-				let val = self.mem.get_mem(self.reg.get_hl());
-				self.reg.a = val;
-				let hl = self.reg.get_hl();
-				self.reg.set_hl(hl + 1);
+				let addr = self.reg.get_hl();
+				self.reg.a = self.mem.get_mem(addr);
+				self.reg.set_hl(addr.wrapping_add(1));
 				(1, 8)
 			}
 			0x2B => {
 				let val = self.reg.get_hl();
-				self.reg.set_hl(val - 1);
+				self.reg.set_hl(val.wrapping_sub(1));
 				(1, 8)
 			}
-			0x2C => self.inc_reg(registers::RegisterName::l),
-			0x2D => self.dec_l(),
+			0x2C => self.inc_reg(RegisterName::l),
+			0x2D => self.dec_reg(RegisterName::l),
 			0x2E => {
 				self.reg.l = self.get_8_pc(1);
 				(2, 8)
 			}
 			0x2F => {
 				self.reg.a = !self.reg.a;
-				let z = self.reg.get_z();
-				let c = self.reg.get_c();
-				self.reg.set_flags(z, true, true, c);
+				self.reg.set_n(true);
+				self.reg.set_h(true);
 				(1, 4)
 			}
 			0x30 => { // JR NC
-				if !self.reg.get_c() {
-					let offset = self.get_8_pc(1) as u16;
-					self.reg.pc += offset & 0x7f;
-					self.reg.pc -= 128 * ((offset & 0x80) >> 7)
-				}
-				(2, 8)
+				let cond = !self.reg.get_c();
+				self.jr(cond)
 			}
 			0x31 => {
 				self.reg.sp = self.get_16_pc(1);
@@ -243,25 +315,24 @@ impl Core {
 			0x32 => {
 				let addr = self.reg.get_hl();
 				self.mem.set_mem(addr, self.reg.a);
-				if addr == 0 {
-					self.reg.set_hl(0xFF);
-				}
-				else {
-					self.reg.set_hl(addr - 1);
-				}
+				self.reg.set_hl(addr.wrapping_sub(1));
 				(1, 8)
 			}
 			0x33 => {
-				self.reg.sp += 1;
+				self.reg.sp = self.reg.sp.wrapping_add(1);
 				(1, 8)
 			}
-
+			0x34 => {
+				let addr = self.reg.get_hl();
+				let operand = self.mem.get_mem(addr);
+				let res = self.inc_8(operand);
+				self.mem.set_mem(addr, res);
+				(1, 12)
+			}
 			0x35 => {
 				let addr = self.reg.get_hl();
 				let operand = self.mem.get_mem(addr);
-				let (res, _carry) = operand.overflowing_sub(1);
-				let c = self.reg.get_c();
-				self.reg.set_flags(res == 0, true, false, c); // half carry
+				let res = self.dec_8(operand);
 				self.mem.set_mem(addr, res);
 				(1, 12)
 			}
@@ -271,31 +342,43 @@ impl Core {
 				self.mem.set_mem(put_addr, put_val);
 				(2, 12)
 			}
-
+			0x37 => { // SCF
+				self.reg.set_n(false);
+				self.reg.set_h(false);
+				self.reg.set_c(true);
+				(1, 4)
+			}
 			0x38 => { // JR C
-				if self.reg.get_c() {
-					let offset = self.get_8_pc(1) as u16;
-					self.reg.pc += offset & 0x7f;
-					self.reg.pc -= 128 * ((offset & 0x80) >> 7)
-				}
-				(2, 8)
+				let cond = self.reg.get_c();
+				self.jr(cond)
 			}
 			0x39 => {
 				let operand = self.reg.sp;
 				self.add_hl(operand)
 			}
-
+			0x3A => {
+				let addr = self.reg.get_hl();
+				self.reg.a = self.mem.get_mem(addr);
+				self.reg.set_hl(addr.wrapping_sub(1));
+				(1, 8)
+			}
 			0x3B => {
-				self.reg.sp = self.reg.sp - 1;
+				self.reg.sp = self.reg.sp.wrapping_sub(1);
 				(1, 8)
 			}
-			0x3C => self.inc_reg(registers::RegisterName::a),
-			0x3D => self.dec_reg(registers::RegisterName::a),
+			0x3C => self.inc_reg(RegisterName::a),
+			0x3D => self.dec_reg(RegisterName::a),
 			0x3E => {
 				self.reg.a = self.get_8_pc(1);
 				(2, 8)
 			}
-
+			0x3F => { // CCF
+				let c = self.reg.get_c();
+				self.reg.set_n(false);
+				self.reg.set_h(false);
+				self.reg.set_c(!c);
+				(1, 4)
+			}
 
 			0x40 => (1, 4),
 			0x41 => {
@@ -513,7 +596,14 @@ impl Core {
 				self.mem.set_mem(addr, val);
 				(1, 8)
 			}
-
+			0x76 => { // HALT
+				if !self.mem.int.enabled && self.mem.int.pending() {
+					self.halt_bug = true;
+				} else {
+					self.state = RunState::Halted;
+				}
+				(1, 4)
+			}
 			0x77 => {
 				let addr = self.reg.get_hl();
 				let val = self.reg.a;
@@ -550,14 +640,87 @@ impl Core {
 				(1, 8)
 			}
 			0x7F => (1, 4),
-			0x80 => self.op_80(),
-			0x81 => self.op_81(),
-			0x82 => self.op_82(),
-			0x83 => self.op_83(),
-			0x84 => self.op_84(),
-			0x85 => self.op_85(),
-			0x87 => self.op_87(),
 
+			0x80 => { // ADD A, B
+				let val = self.reg.b;
+				self.add_a(val);
+				(1, 4)
+			}
+			0x81 => { // ADD A, C
+				let val = self.reg.c;
+				self.add_a(val);
+				(1, 4)
+			}
+			0x82 => { // ADD A, D
+				let val = self.reg.d;
+				self.add_a(val);
+				(1, 4)
+			}
+			0x83 => { // ADD A, E
+				let val = self.reg.e;
+				self.add_a(val);
+				(1, 4)
+			}
+			0x84 => { // ADD A, H
+				let val = self.reg.h;
+				self.add_a(val);
+				(1, 4)
+			}
+			0x85 => { // ADD A, L
+				let val = self.reg.l;
+				self.add_a(val);
+				(1, 4)
+			}
+			0x86 => { // ADD A, (HL)
+				let val = self.mem.get_mem(self.reg.get_hl());
+				self.add_a(val);
+				(1, 8)
+			}
+			0x87 => { // ADD A, A
+				let val = self.reg.a;
+				self.add_a(val);
+				(1, 4)
+			}
+			0x88 => { // ADC A, B
+				let val = self.reg.b;
+				self.adc_a(val);
+				(1, 4)
+			}
+			0x89 => { // ADC A, C
+				let val = self.reg.c;
+				self.adc_a(val);
+				(1, 4)
+			}
+			0x8A => { // ADC A, D
+				let val = self.reg.d;
+				self.adc_a(val);
+				(1, 4)
+			}
+			0x8B => { // ADC A, E
+				let val = self.reg.e;
+				self.adc_a(val);
+				(1, 4)
+			}
+			0x8C => { // ADC A, H
+				let val = self.reg.h;
+				self.adc_a(val);
+				(1, 4)
+			}
+			0x8D => { // ADC A, L
+				let val = self.reg.l;
+				self.adc_a(val);
+				(1, 4)
+			}
+			0x8E => { // ADC A, (HL)
+				let val = self.mem.get_mem(self.reg.get_hl());
+				self.adc_a(val);
+				(1, 8)
+			}
+			0x8F => { // ADC A, A
+				let val = self.reg.a;
+				self.adc_a(val);
+				(1, 4)
+			}
 			0x90 => { // SUB A, B
 				let val = self.reg.b;
 				self.sub_a(val);
@@ -591,314 +754,321 @@ impl Core {
 			0x96 => { // SUB A, (HL)
 				let val = self.mem.get_mem(self.reg.get_hl());
 				self.sub_a(val);
+				(1, 8)
+			}
+			0x97 => { // SUB A, A
+				let val = self.reg.a;
+				self.sub_a(val);
 				(1, 4)
 			}
-
-			0x9B => {
+			0x98 => { // SBC A, B
+				let val = self.reg.b;
+				self.subc_a(val);
+				(1, 4)
+			}
+			0x99 => { // SBC A, C
+				let val = self.reg.c;
+				self.subc_a(val);
+				(1, 4)
+			}
+			0x9A => { // SBC A, D
+				let val = self.reg.d;
+				self.subc_a(val);
+				(1, 4)
+			}
+			0x9B => { // SBC A, E
 				let val = self.reg.e;
 				self.subc_a(val);
 				(1, 4)
 			}
-
-			0xA0 => {
-				let operand = self.reg.b;
-				self.and_a(operand)
+			0x9C => { // SBC A, H
+				let val = self.reg.h;
+				self.subc_a(val);
+				(1, 4)
+			}
+			0x9D => { // SBC A, L
+				let val = self.reg.l;
+				self.subc_a(val);
+				(1, 4)
+			}
+			0x9E => { // SBC A, (HL)
+				let val = self.mem.get_mem(self.reg.get_hl());
+				self.subc_a(val);
+				(1, 8)
+			}
+			0x9F => { // SBC A, A
+				let val = self.reg.a;
+				self.subc_a(val);
+				(1, 4)
+			}
+			0xA0 => { // AND A, B
+				let val = self.reg.b;
+				self.and_a(val);
+				(1, 4)
+			}
+			0xA1 => { // AND A, C
+				let val = self.reg.c;
+				self.and_a(val);
+				(1, 4)
+			}
+			0xA2 => { // AND A, D
+				let val = self.reg.d;
+				self.and_a(val);
+				(1, 4)
+			}
+			0xA3 => { // AND A, E
+				let val = self.reg.e;
+				self.and_a(val);
+				(1, 4)
+			}
+			0xA4 => { // AND A, H
+				let val = self.reg.h;
+				self.and_a(val);
+				(1, 4)
+			}
+			0xA5 => { // AND A, L
+				let val = self.reg.l;
+				self.and_a(val);
+				(1, 4)
+			}
+			0xA6 => { // AND A, (HL)
+				let val = self.mem.get_mem(self.reg.get_hl());
+				self.and_a(val);
+				(1, 8)
+			}
+			0xA7 => { // AND A, A
+				let val = self.reg.a;
+				self.and_a(val);
+				(1, 4)
+			}
+			0xA8 => { // XOR A, B
+				let val = self.reg.b;
+				self.xor_a(val);
+				(1, 4)
 			}
-			0xA1 => {
-				let operand = self.reg.c;
-				self.and_a(operand)
+			0xA9 => { // XOR A, C
+				let val = self.reg.c;
+				self.xor_a(val);
+				(1, 4)
 			}
-			0xA2 => {
-				let operand = self.reg.d;
-				self.and_a(operand)
+			0xAA => { // XOR A, D
+				let val = self.reg.d;
+				self.xor_a(val);
+				(1, 4)
 			}
-			0xA3 => {
-				let operand = self.reg.e;
-				self.and_a(operand)
+			0xAB => { // XOR A, E
+				let val = self.reg.e;
+				self.xor_a(val);
+				(1, 4)
 			}
-			0xA4 => {
-				let operand = self.reg.h;
-				self.and_a(operand)
+			0xAC => { // XOR A, H
+				let val = self.reg.h;
+				self.xor_a(val);
+				(1, 4)
 			}
-			0xA5 => {
-				let operand = self.reg.l;
-				self.and_a(operand)
+			0xAD => { // XOR A, L
+				let val = self.reg.l;
+				self.xor_a(val);
+				(1, 4)
 			}
-			0xA6 => {
-				let operand = self.mem.get_mem(self.reg.get_hl());
-				self.and_a(operand);
-				(1,8)
+			0xAE => { // XOR A, (HL)
+				let val = self.mem.get_mem(self.reg.get_hl());
+				self.xor_a(val);
+				(1, 8)
 			}
-			0xA7 => {
-				let operand = self.reg.a;
-				self.and_a(operand)
+			0xAF => { // XOR A, A
+				let val = self.reg.a;
+				self.xor_a(val);
+				(1, 4)
 			}
-			0xA8 => {
-				let operand = self.reg.b;
-				self.xor_a(operand)
+			0xB0 => { // OR A, B
+				let val = self.reg.b;
+				self.or_a(val);
+				(1, 4)
 			}
-			0xA9 => {
-				let operand = self.reg.c;
-				self.xor_a(operand)
+			0xB1 => { // OR A, C
+				let val = self.reg.c;
+				self.or_a(val);
+				(1, 4)
 			}
-			0xAA => {
-				let operand = self.reg.d;
-				self.xor_a(operand)
+			0xB2 => { // OR A, D
+				let val = self.reg.d;
+				self.or_a(val);
+				(1, 4)
 			}
-			0xAB => {
-				let operand = self.reg.e;
-				self.xor_a(operand)
+			0xB3 => { // OR A, E
+				let val = self.reg.e;
+				self.or_a(val);
+				(1, 4)
 			}
-			0xAC => {
-				let operand = self.reg.h;
-				self.xor_a(operand)
+			0xB4 => { // OR A, H
+				let val = self.reg.h;
+				self.or_a(val);
+				(1, 4)
 			}
-			0xAD => {
-				let operand = self.reg.l;
-				self.xor_a(operand)
+			0xB5 => { // OR A, L
+				let val = self.reg.l;
+				self.or_a(val);
+				(1, 4)
 			}
-			0xAE => {
-				let operand = self.mem.get_mem(self.reg.get_hl());
-				self.xor_a(operand);
-				(1,8)
+			0xB6 => { // OR A, (HL)
+				let val = self.mem.get_mem(self.reg.get_hl());
+				self.or_a(val);
+				(1, 8)
 			}
-			0xAF => { // A xor A -> A
-				self.reg.a = 0;
-				self.reg.set_flags(true, false, false, false);
+			0xB7 => { // OR A, A
+				let val = self.reg.a;
+				self.or_a(val);
 				(1, 4)
 			}
-			0xB0 => {
-				let operand = self.reg.b;
-				self.or_a(operand)
+			0xB8 => { // CP A, B
+				let val = self.reg.b;
+				self.cp_a(val);
+				(1, 4)
 			}
-			0xB1 => {
-				let operand = self.reg.c;
-				self.or_a(operand)
+			0xB9 => { // CP A, C
+				let val = self.reg.c;
+				self.cp_a(val);
+				(1, 4)
 			}
-			0xB2 => {
-				let operand = self.reg.d;
-				self.or_a(operand)
+			0xBA => { // CP A, D
+				let val = self.reg.d;
+				self.cp_a(val);
+				(1, 4)
 			}
-			0xB3 => {
-				let operand = self.reg.e;
-				self.or_a(operand)
+			0xBB => { // CP A, E
+				let val = self.reg.e;
+				self.cp_a(val);
+				(1, 4)
 			}
-			0xB4 => {
-				let operand = self.reg.h;
-				self.or_a(operand)
+			0xBC => { // CP A, H
+				let val = self.reg.h;
+				self.cp_a(val);
+				(1, 4)
 			}
-			0xB5 => {
-				let operand = self.reg.l;
-				self.or_a(operand)
+			0xBD => { // CP A, L
+				let val = self.reg.l;
+				self.cp_a(val);
+				(1, 4)
 			}
-			0xB6 => {
-				let operand = self.mem.get_mem(self.reg.get_hl());
-				self.reg.a = self.reg.a | operand;
-				let z = self.reg.a == 0;
-				self.reg.set_flags(z, false, false, false);
+			0xBE => { // CP A, (HL)
+				let val = self.mem.get_mem(self.reg.get_hl());
+				self.cp_a(val);
 				(1, 8)
 			}
-			0xB7 => {
-				let operand = self.reg.a;
-				self.or_a(operand)
+			0xBF => { // CP A, A
+				let val = self.reg.a;
+				self.cp_a(val);
+				(1, 4)
 			}
 
-			0xC0 => {
-				if !self.reg.get_z() {
-					self.reg.pc = self.pop();
-					(0, 8)
-				} else {
-					(1, 8)
-				}
+			0xC0 => { // RET NZ
+				let cond = !self.reg.get_z();
+				self.ret(cond)
 			}
 			0xC1 => {
 				let popped = self.pop();
 				self.reg.set_bc(popped);
 				(1, 12)
 			}
-			0xC2 => {
-				if !self.reg.get_z() {
-					self.reg.pc = self.get_16_pc(1);
-					(0, 12)
-				} else {
-					(3, 12)
-				}
-			}
-			0xC3 => {
-				self.reg.pc = self.get_16_pc(1);
-				(0, 4)
+			0xC2 => { // JP NZ
+				let cond = !self.reg.get_z();
+				self.jp(cond)
 			}
+			0xC3 => self.jp(true),
 			0xC4 => { // CALL NZ
-				if !self.reg.get_z() {
-					let pc = self.reg.pc;
-					self.reg.pc = self.get_16_pc(1) - 2;
-					self.push(pc + 2); // +2 so it will ret to the next op
-					(2, 12)
-				}
-				else {
-					(3, 12)
-				}
+				let cond = !self.reg.get_z();
+				self.call(cond)
 			}
 			0xC5 => {
 				let val = self.reg.get_bc();
 				self.push(val);
 				(1, 16)
 			}
-			0xC6 => {
+			0xC6 => { // ADD A, #
 				let val = self.get_8_pc(1);
-				let (res, _carry) = self.reg.a.overflowing_add(val);
-				self.reg.a = res;
-				self.reg.set_flags(res == 0, false, false, _carry); // Fix half carry
+				self.add_a(val);
 				(2, 8)
 			}
-			0xC7 => {
-				let addr = self.reg.pc;
-				self.push(addr);
-				self.reg.pc = 0;
-				(1, 32)
-			}
-			0xC8 => {
-				if self.reg.get_z() {
-					self.reg.pc = self.pop();
-					(0, 8)
-				} else {
-					(1, 8)
-				}
+			0xC7 => self.rst(0x00),
+			0xC8 => { // RET Z
+				let cond = self.reg.get_z();
+				self.ret(cond)
 			}
 			0xC9 => {
 				self.reg.pc = self.pop();
-				(1, 8)
+				(0, 16)
 			}
-			0xCA => {
-				if self.reg.get_z() {
-					self.reg.pc = self.get_16_pc(1);
-					(0, 12)
-				} else {
-					(3, 12)
-				}
+			0xCA => { // JP Z
+				let cond = self.reg.get_z();
+				self.jp(cond)
 			}
 			0xCB => {
 				let op = self.get_8_pc(1);
 				self.handle_cb(op)
 			}
 			0xCC => { // CALL Z
-				if self.reg.get_z() {
-					let pc = self.reg.pc;
-					self.reg.pc = self.get_16_pc(1) - 2;
-					self.push(pc + 2); // +2 so it will ret to the next op
-					(2, 12)
-				}
-				else {
-					(3, 12)
-				}
-			}
-			0xCD => {
-				let pc = self.reg.pc;
-				self.reg.pc = self.get_16_pc(1) - 2;
-				self.push(pc + 2); // +2 so it will ret to the next op
-				(2, 12)
+				let cond = self.reg.get_z();
+				self.call(cond)
 			}
-			0xCE => { // ADC A,#
-				let (operand, op_Carry) = self.get_8_pc(1).overflowing_add(self.reg.get_c() as u8);
-				let hc = check_add_half_carry(self.reg.a, operand);
-				let (res, carry) = self.reg.a.overflowing_add(operand);
-				self.reg.a = res;
-				self.reg.set_flags(res == 0, false, hc, carry);
+			0xCD => self.call(true),
+			0xCE => { // ADC A, #
+				let val = self.get_8_pc(1);
+				self.adc_a(val);
 				(2, 8)
 			}
-			0xCF => {
-				let addr = self.reg.pc;
-				self.push(addr + 1);
-				self.reg.pc = 0x08;
-				(0, 32)
-			}
-			0xD0 => {
-				if !self.reg.get_c() {
-					self.reg.pc = self.pop();
-					(0, 8)
-				} else {
-					(1, 8)
-				}
+			0xCF => self.rst(0x08),
+			0xD0 => { // RET NC
+				let cond = !self.reg.get_c();
+				self.ret(cond)
 			}
 			0xD1 => {
 				let popped = self.pop();
 				self.reg.set_de(popped);
 				(1, 12)
 			}
-			0xD2 => {
-				if !self.reg.get_c() {
-					self.reg.pc = self.get_16_pc(1);
-					(0, 12)
-				} else {
-					(3, 12)
-				}
+			0xD2 => { // JP NC
+				let cond = !self.reg.get_c();
+				self.jp(cond)
 			}
-
 			0xD4 => { // CALL NC
-				if !self.reg.get_c() {
-					let pc = self.reg.pc;
-					self.reg.pc = self.get_16_pc(1) - 2;
-					self.push(pc + 2); // +2 so it will ret to the next op
-					(2, 12)
-				}
-				else {
-					(3, 12)
-				}
+				let cond = !self.reg.get_c();
+				self.call(cond)
 			}
 			0xD5 => {
 				let val = self.reg.get_de();
 				self.push(val);
 				(1, 16)
 			}
-			0xD6 => {
+			0xD6 => { // SUB A, #
 				let val = self.get_8_pc(1);
-				let (res, _carry) = self.reg.a.overflowing_sub(val);
-				self.reg.a = res;
-				self.reg.set_flags(res == 0, true, false, !_carry); // Fix half carry
+				self.sub_a(val);
 				(2, 8)
 			}
-			0xD7 => {
-				let addr = self.reg.pc;
-				self.push(addr + 1);
-				self.reg.pc = 0x10;
-				(0, 32)
+			0xD7 => self.rst(0x10),
+			0xD8 => { // RET C
+				let cond = self.reg.get_c();
+				self.ret(cond)
 			}
-			0xD8 => {
-				if self.reg.get_c() {
-					self.reg.pc = self.pop();
-					(0, 8)
-				} else {
-					(1, 8)
-				}
+			0xD9 => { // RETI
+				self.reg.pc = self.pop();
+				self.mem.int.toggle(true);
+				(0, 16)
 			}
-
-			0xDA => {
-				if self.reg.get_c() {
-					self.reg.pc = self.get_16_pc(1);
-					(0, 12)
-				} else {
-					(3, 12)
-				}
+			0xDA => { // JP C
+				let cond = self.reg.get_c();
+				self.jp(cond)
 			}
-
 			0xDC => { // CALL C
-				if self.reg.get_c() {
-					let pc = self.reg.pc;
-					self.reg.pc = self.get_16_pc(1) - 2;
-					self.push(pc + 2); // +2 so it will ret to the next op
-					(2, 12)
-				}
-				else {
-					(3, 12)
-				}
+				let cond = self.reg.get_c();
+				self.call(cond)
 			}
-
-			0xDF => {
-				let addr = self.reg.pc;
-				self.push(addr + 1);
-				self.reg.pc = 0x18;
-				(0, 32)
+			0xDE => { // SBC A, #
+				let val = self.get_8_pc(1);
+				self.subc_a(val);
+				(2, 8)
 			}
+			0xDF => self.rst(0x18),
 			0xE0 => {
 				let addr = 0xFF00 + (self.get_8_pc(1) as u16);
 				self.mem.set_mem(addr, self.reg.a);
@@ -914,46 +1084,37 @@ impl Core {
 				self.mem.set_mem(addr, self.reg.a);
 				(1, 8)
 			}
-
 			0xE5 => {
 				let val = self.reg.get_hl();
 				self.push(val);
 				(1, 16)
 			}
-			0xE6 => {
-				let operand = self.get_8_pc(1);
-				self.and_a(operand);
+			0xE6 => { // AND A, #
+				let val = self.get_8_pc(1);
+				self.and_a(val);
 				(2, 8)
 			}
-			0xE7 => {
-				let addr = self.reg.pc;
-				self.push(addr + 1);
-				self.reg.pc = 0x20;
-				(0, 32)
+			0xE7 => self.rst(0x20),
+			0xE8 => { // ADD SP, e
+				let offset = self.get_8_pc(1);
+				self.reg.sp = self.sp_offset(offset);
+				(2, 16)
 			}
-
 			0xE9 => {
 				self.reg.pc = self.reg.get_hl();
 				(0, 4)
 			}
-
 			0xEA => {
 				let put_addr = self.get_16_pc(1);
 				self.mem.set_mem(put_addr, self.reg.a);
 				(3, 16)
 			}
-
-			0xEE => {
-				let operand = self.get_8_pc(1);
-				self.xor_a(operand);
+			0xEE => { // XOR A, #
+				let val = self.get_8_pc(1);
+				self.xor_a(val);
 				(2, 8)
 			}
-			0xEF => {
-				let addr = self.reg.pc;
-				self.push(addr + 1);
-				self.reg.pc = 0x28;
-				(0, 32)
-			}
+			0xEF => self.rst(0x28),
 			0xF0 => {
 				let addr = 0xFF00 + (self.get_8_pc(1) as u16);
 				self.reg.a = self.mem.get_mem(addr);
@@ -961,63 +1122,124 @@ impl Core {
 			}
 			0xF1 => {
 				let popped = self.pop();
-				self.reg.set_af(popped);
+				self.reg.set_af(popped & 0xFFF0);
 				(1, 12)
 			}
-
+			0xF2 => {
+				let addr = 0xFF00 + (self.reg.c as u16);
+				self.reg.a = self.mem.get_mem(addr);
+				(1, 8)
+			}
 			0xF3 => {
-				self.int.toggle(false);
+				self.mem.int.toggle(false);
 				(1, 4)
 			}
-
 			0xF5 => {
 				let val = self.reg.get_af();
 				self.push(val);
 				(1, 16)
 			}
-
-			0xF7 => {
-				let addr = self.reg.pc;
-				self.push(addr + 1);
-				self.reg.pc = 0x30;
-				(0, 32)
+			0xF6 => { // OR A, #
+				let val = self.get_8_pc(1);
+				self.or_a(val);
+				(2, 8)
+			}
+			0xF7 => self.rst(0x30),
+			0xF8 => { // LD HL, SP+e
+				let offset = self.get_8_pc(1);
+				let res = self.sp_offset(offset);
+				self.reg.set_hl(res);
+				(2, 12)
+			}
+			0xF9 => {
+				self.reg.sp = self.reg.get_hl();
+				(1, 8)
 			}
-
 			0xFA => {
 				let addr = self.get_16_pc(1);
 				self.reg.a = self.mem.get_mem(addr);
 				(3, 16)
 			}
 			0xFB => {
-				self.int.toggle(true);
+				self.mem.int.schedule_enable();
 				(1, 4)
-			}			
-
+			}
 			0xFE => { // CP A, #
-				let cmp = self.get_8_pc(1);
-				let a = self.reg.a;
-				self.reg.set_z(a == cmp);
-				self.reg.set_n(true);
-				self.reg.set_h(check_sub_half_carry(a, cmp));
-				self.reg.set_c(a < cmp);
+				let val = self.get_8_pc(1);
+				self.cp_a(val);
 				(2, 8)
 			}
-			0xFF => {
-				let addr = self.reg.pc;
-				self.push(addr + 1);
-				self.reg.pc = 0x38;
-				(0, 32)
+			0xFF => self.rst(0x38),
+			// 0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC and 0xFD hang the CPU
+			_ => {
+				log!(Cpu, Error, "Locked up on illegal opcode {:02X} at {:04X}", ins, self.reg.pc);
+				self.state = RunState::Locked { opcode: ins, pc: self.reg.pc };
+				(0, 4)
 			}
-			_ => panic!("Instruction {:2X} at {:2X} not implemented!", ins, self.reg.pc)
 		};
-		self.reg.disp_state();
-		println!("");
-		self.reg.pc += _numsteps.0;
+		self.reg.pc = self.reg.pc.wrapping_add(_numsteps.0);
 		self.mem.update(_numsteps.1);
+		// An EI delay that survived this instruction (wasn't cancelled by DI) now lands
+		if enable_after && self.mem.int.ei_pending {
+			self.mem.int.toggle(true);
+		}
+		self.end_watch();
+		Ok(())
+	}
+
+	// Stops watching once the instruction is done, anything it tripped waits in take_watch_hit
+	fn end_watch(&mut self) {
+		self.mem.watch_pc = None;
+	}
+
+	// Reference logs are usually taken with LY stuck at 0x90 so they don't depend on PPU timing
+	pub fn trace_to(&mut self, out:Box<dyn Write>, stub_ly:bool) {
+		self.trace = Some(trace::Trace::create(out));
+		self.mem.disp.stub_ly = stub_ly;
+	}
+
+	// Ok while the CPU can still execute, the lock-up cause once an undefined opcode has hung it
+	pub fn status(&self) -> Result<(), EmuError> {
+		match self.state {
+			RunState::Locked { opcode, pc } => Err(EmuError::IllegalOpcode { opcode, pc }),
+			_ => Ok(()),
+		}
+	}
+
+	// The last completed frame as BGP/OBP mapped shades, 0 (white) to 3 (black)
+	pub fn framebuffer(&self) -> &[u8; display::SCREEN_WIDTH * display::SCREEN_HEIGHT] {
+		&self.mem.disp.framebuffer
+	}
+
+	pub fn press(&mut self, button:Button) {
+		self.mem.joypad.press(button, &mut self.mem.int);
+	}
+
+	pub fn release(&mut self, button:Button) {
+		self.mem.joypad.release(button);
+	}
+
+	// Jumps to the highest priority pending interrupt if IME allows it
+	fn service_interrupt(&mut self) -> bool {
+		if !self.mem.int.enabled {
+			return false;
+		}
+		match self.mem.int.highest_pending() {
+			Some(int) => {
+				self.mem.int.toggle(false);
+				self.mem.int.clear(int);
+				let pc = self.reg.pc;
+				self.push(pc);
+				self.reg.pc = int.vector();
+				self.mem.update(20);
+				true
+			}
+			None => false
+		}
 	}
 
 	fn get_8_pc(&mut self, offset:u16) -> u8 {
-		self.mem.get_mem(self.reg.pc + offset)
+		self.mem.fetch(self.reg.pc.wrapping_add(offset))
 	}
 
 	fn get_16_pc(&mut self, offset:u16) -> u16 {
@@ -1025,85 +1247,233 @@ impl Core {
 	}
 
 	fn push(&mut self, value:u16) {
+		self.reg.sp = self.reg.sp.wrapping_sub(1);
+		self.mem.set_mem(self.reg.sp, ((value & 0xFF00) >> 8) as u8);
+		self.reg.sp = self.reg.sp.wrapping_sub(1);
 		self.mem.set_mem(self.reg.sp, (value & 0x00FF) as u8);
-		self.mem.set_mem(self.reg.sp - 1, ((value & 0xFF00) >> 8) as u8);
-		self.reg.sp -= 2;
 	}
 
 	fn pop(&mut self) -> u16 {
-		let low = self.mem.get_mem(self.reg.sp + 2) as u16;
-		let high = self.mem.get_mem(self.reg.sp + 1) as u16;
-		self.reg.sp += 2;
+		let low = self.mem.get_mem(self.reg.sp) as u16;
+		self.reg.sp = self.reg.sp.wrapping_add(1);
+		let high = self.mem.get_mem(self.reg.sp) as u16;
+		self.reg.sp = self.reg.sp.wrapping_add(1);
 		(high << 8) + low
 	}
 
-	fn or_a(&mut self, operand:u8) -> (u16, u64) {
-		self.reg.a = self.reg.a | operand;
-		let z = self.reg.a == 0;
-		self.reg.set_flags(z, false, false, false);
-		(1, 4)
+	fn jr(&mut self, cond:bool) -> (u16, u64) {
+		if cond {
+			let offset = self.get_8_pc(1) as i8;
+			self.reg.pc = self.reg.pc.wrapping_add(offset as u16);
+			(2, 12)
+		} else {
+			(2, 8)
+		}
+	}
+
+	fn jp(&mut self, cond:bool) -> (u16, u64) {
+		if cond {
+			self.reg.pc = self.get_16_pc(1);
+			(0, 16)
+		} else {
+			(3, 12)
+		}
+	}
+
+	fn call(&mut self, cond:bool) -> (u16, u64) {
+		if cond {
+			let ret_addr = self.reg.pc.wrapping_add(3);
+			self.reg.pc = self.get_16_pc(1);
+			self.push(ret_addr);
+			(0, 24)
+		} else {
+			(3, 12)
+		}
+	}
+
+	fn ret(&mut self, cond:bool) -> (u16, u64) {
+		if cond {
+			self.reg.pc = self.pop();
+			(0, 20)
+		} else {
+			(1, 8)
+		}
+	}
+
+	fn rst(&mut self, addr:u16) -> (u16, u64) {
+		let ret_addr = self.reg.pc.wrapping_add(1);
+		self.push(ret_addr);
+		self.reg.pc = addr;
+		(0, 16)
+	}
+
+	fn add_a(&mut self, val:u8) {
+		let hc = check_add_half_carry(self.reg.a, val);
+		let (res, carry) = self.reg.a.overflowing_add(val);
+		self.reg.a = res;
+		self.reg.set_flags(res == 0, false, hc, carry);
+	}
+
+	fn adc_a(&mut self, val:u8) {
+		let c = self.reg.get_c() as u8;
+		let hc = (self.reg.a & 0xF) + (val & 0xF) + c > 0xF;
+		let carry = (self.reg.a as u16) + (val as u16) + (c as u16) > 0xFF;
+		let res = self.reg.a.wrapping_add(val).wrapping_add(c);
+		self.reg.a = res;
+		self.reg.set_flags(res == 0, false, hc, carry);
+	}
+
+	fn sub_a(&mut self, val:u8) {
+		let hc = check_sub_half_carry(self.reg.a, val);
+		let (res, carry) = self.reg.a.overflowing_sub(val);
+		self.reg.a = res;
+		self.reg.set_flags(res == 0, true, hc, carry);
+	}
+
+	fn subc_a(&mut self, val:u8) {
+		let c = self.reg.get_c() as u8;
+		let hc = (self.reg.a & 0xF) < (val & 0xF) + c;
+		let carry = (self.reg.a as u16) < (val as u16) + (c as u16);
+		let res = self.reg.a.wrapping_sub(val).wrapping_sub(c);
+		self.reg.a = res;
+		self.reg.set_flags(res == 0, true, hc, carry);
 	}
 
-	fn and_a(&mut self, operand:u8) -> (u16, u64) {
-		self.reg.a = self.reg.a & operand;
+	fn and_a(&mut self, val:u8) {
+		self.reg.a &= val;
 		let z = self.reg.a == 0;
 		self.reg.set_flags(z, false, true, false);
-		(1, 4)
 	}
 
-	fn xor_a(&mut self, operand:u8) -> (u16, u64) {
-		self.reg.a ^= operand;
+	fn xor_a(&mut self, val:u8) {
+		self.reg.a ^= val;
 		let z = self.reg.a == 0;
 		self.reg.set_flags(z, false, false, false);
-		(1, 4)
+	}
+
+	fn or_a(&mut self, val:u8) {
+		self.reg.a |= val;
+		let z = self.reg.a == 0;
+		self.reg.set_flags(z, false, false, false);
+	}
+
+	fn cp_a(&mut self, val:u8) {
+		let a = self.reg.a;
+		self.reg.set_flags(a == val, true, check_sub_half_carry(a, val), a < val);
 	}
 
 	fn add_hl(&mut self, operand:u16) -> (u16, u64) {
-		let hc = check_add_half_carry_16(self.reg.get_hl(), operand);
-		let operand = self.reg.get_hl();
-		let (res, carry) = operand.overflowing_add(operand);
+		let hl = self.reg.get_hl();
+		let hc = check_add_half_carry_16(hl, operand);
+		let (res, carry) = hl.overflowing_add(operand);
 		self.reg.set_hl(res);
 		let z = self.reg.get_z();
 		self.reg.set_flags(z, false, hc, carry);
 		(1, 8)
 	}
 
+	// SP + signed immediate, shared by ADD SP,e and LD HL,SP+e
+	fn sp_offset(&mut self, offset:u8) -> u16 {
+		let sp = self.reg.sp;
+		let hc = (sp & 0x0F) + (offset as u16 & 0x0F) > 0x0F;
+		let carry = (sp & 0xFF) + (offset as u16) > 0xFF;
+		self.reg.set_flags(false, false, hc, carry);
+		sp.wrapping_add(offset as i8 as u16)
+	}
+
+	fn daa(&mut self) -> (u16, u64) {
+		let mut a = self.reg.a;
+		let mut carry = self.reg.get_c();
+		if !self.reg.get_n() {
+			if carry || a > 0x99 {
+				a = a.wrapping_add(0x60);
+				carry = true;
+			}
+			if self.reg.get_h() || (a & 0x0F) > 0x09 {
+				a = a.wrapping_add(0x06);
+			}
+		} else {
+			if carry {
+				a = a.wrapping_sub(0x60);
+			}
+			if self.reg.get_h() {
+				a = a.wrapping_sub(0x06);
+			}
+		}
+		self.reg.a = a;
+		let n = self.reg.get_n();
+		self.reg.set_flags(a == 0, n, false, carry);
+		(1, 4)
+	}
+
+	fn rlc(&mut self, operand:u8) -> u8 {
+		let res = operand.rotate_left(1);
+		self.reg.set_flags(res == 0, false, false, operand & 0x80 != 0);
+		res
+	}
+
+	fn rrc(&mut self, operand:u8) -> u8 {
+		let res = operand.rotate_right(1);
+		self.reg.set_flags(res == 0, false, false, operand & 0x01 != 0);
+		res
+	}
+
+	fn rl(&mut self, operand:u8) -> u8 {
+		let res = (operand << 1) | (self.reg.get_c() as u8);
+		self.reg.set_flags(res == 0, false, false, operand & 0x80 != 0);
+		res
+	}
+
+	fn rr(&mut self, operand:u8) -> u8 {
+		let res = (operand >> 1) | ((self.reg.get_c() as u8) << 7);
+		self.reg.set_flags(res == 0, false, false, operand & 0x01 != 0);
+		res
+	}
+
 	fn handle_cb(&mut self, op:u8) -> (u16, u64) {
-		let mut operand = match op & 0x0F {
-			0x0 | 0x8 => self.reg.b,
-			0x1 | 0x9 => self.reg.c,
-			0x2 | 0xA => self.reg.d,
-			0x3 | 0xB => self.reg.e,
-			0x4 | 0xC => self.reg.h,
-			0x5 | 0xD => self.reg.l,
-
-			0x7 | 0xF => self.reg.a,
-			_ => panic!("Invalid CB operand")
+		let hl = self.reg.get_hl();
+		let operand = match op & 0x07 {
+			0x0 => self.reg.b,
+			0x1 => self.reg.c,
+			0x2 => self.reg.d,
+			0x3 => self.reg.e,
+			0x4 => self.reg.h,
+			0x5 => self.reg.l,
+			0x6 => self.mem.get_mem(hl),
+			_ => self.reg.a,
 		};
 
-		operand = match op {
-			0x00 ..= 0x07 => operand, // Rotate left through carry
-			0x07 ..= 0x0F => operand, // Rotate right through carry
-			
-			0x19 ..= 0x1D => {
-				let b0 = operand & 0x1;
-				let mut res = operand >> 1;
-				res |= (self.reg.get_c() as u8) << 7;
-				self.reg.set_flags(res == 0, false, false, b0 == 0x1);
+		let res = match op {
+			0x00 ..= 0x07 => self.rlc(operand), // Rotate left
+			0x08 ..= 0x0F => self.rrc(operand), // Rotate right
+			0x10 ..= 0x17 => self.rl(operand), // Rotate left through carry
+			0x18 ..= 0x1F => self.rr(operand), // Rotate right through carry
+			0x20 ..= 0x27 => { // Shift left arithmetic
+				let res = operand << 1;
+				self.reg.set_flags(res == 0, false, false, operand & 0x80 != 0);
+				res
+			}
+			0x28 ..= 0x2F => { // Shift right arithmetic
+				let res = (operand >> 1) | (operand & 0x80);
+				self.reg.set_flags(res == 0, false, false, operand & 0x01 != 0);
 				res
 			}
-
 			0x30 ..= 0x37 => { // Swap
 				let res = ((operand & 0xF0) >> 4) + ((operand & 0x0F) << 4);
 				self.reg.set_flags(res == 0, false, false, false);
 				res
 			}
-			0x38 ..= 0x3D | 0x3F => {
-				let b0 = operand & 0x1;
+			0x38 ..= 0x3F => { // Shift right logical
 				let res = operand >> 1;
-				self.reg.set_flags(res == 0, false, false, b0 == 0x1);
+				self.reg.set_flags(res == 0, false, false, operand & 0x01 != 0);
 				res
 			}
+			0x40 ..= 0x7F => { // Test bit, nothing is written back
+				let bit = (op >> 3) & 0x07;
+				let c = self.reg.get_c();
+				self.reg.set_flags(operand & (1 << bit) == 0, false, true, c);
+				return if op & 0x07 == 0x6 { (2, 12) } else { (2, 8) };
+			}
 
 			0x80 ..= 0x87 => operand & 0xFE, // Reset bit 0
 			0x88 ..= 0x8F => operand & 0xFD, // Reset bit 1
@@ -1115,241 +1485,70 @@ impl Core {
 			0xB8 ..= 0xBF => operand & 0x7F, // Reset bit 7
 			0xC0 ..= 0xC7 => operand | 0x01, // Set bit 0
 			0xC8 ..= 0xCF => operand | 0x02, // Set bit 1
-			0xD0 ..= 0xD7 => operand | 0x04, //	Set bit 2
+			0xD0 ..= 0xD7 => operand | 0x04, // Set bit 2
 			0xD8 ..= 0xDF => operand | 0x08, // Set bit 3
 			0xE0 ..= 0xE7 => operand | 0x10, // Set bit 4
 			0xE8 ..= 0xEF => operand | 0x20, // Set bit 5
 			0xF0 ..= 0xF7 => operand | 0x40, // Set bit 6
 			0xF8 ..= 0xFF => operand | 0x80, // Set bit 7
-			_ => panic!("Unimplemented CB operation {:2X}", op)
-		};
-		match op & 0x0F {
-			0x0 | 0x8 => self.reg.b = operand,
-			0x1 | 0x9 => self.reg.c = operand,
-			0x2 | 0xA => self.reg.d = operand,
-			0x3 | 0xB => self.reg.e = operand,
-			0x4 | 0xC => self.reg.h = operand,
-			0x5 | 0xD => self.reg.l = operand,
-
-			0x7 | 0xF => self.reg.a = operand,
-			_ => panic!("Invalid CB operand")
-		};
-		(2, 8) // TODO: Fix timing for (HL) operations
-	}
-
-	fn inc_reg(&mut self, reg_name:registers::RegisterName) -> (u16, u64) {
-		let operand = match reg_name {
-			registers::RegisterName::a => self.reg.a,
-			registers::RegisterName::b => self.reg.b,
-			registers::RegisterName::c => self.reg.c,
-			registers::RegisterName::d => self.reg.d,
-			registers::RegisterName::e => self.reg.e,
-			registers::RegisterName::h => self.reg.h,
-			registers::RegisterName::l => self.reg.l,			
-			_ => panic!("Invalid register in inc")
 		};
-		let (res, carry) = operand.overflowing_add(1);
-		let cf = self.reg.get_c();
-		let half = self.reg.e & 0x0F == 0x0F;
-		self.reg.set_flags(carry, false, half, cf);
-		match reg_name {
-			registers::RegisterName::a => self.reg.a = res,
-			registers::RegisterName::b => self.reg.b = res,
-			registers::RegisterName::c => self.reg.c = res,
-			registers::RegisterName::d => self.reg.d = res,
-			registers::RegisterName::e => self.reg.e = res,
-			registers::RegisterName::h => self.reg.h = res,
-			registers::RegisterName::l => self.reg.l = res,			
-			_ => panic!("Invalid register in inc")
+		match op & 0x07 {
+			0x0 => self.reg.b = res,
+			0x1 => self.reg.c = res,
+			0x2 => self.reg.d = res,
+			0x3 => self.reg.e = res,
+			0x4 => self.reg.h = res,
+			0x5 => self.reg.l = res,
+			0x6 => {
+				self.mem.set_mem(hl, res);
+				return (2, 16);
+			}
+			_ => self.reg.a = res,
 		};
-		(1, 4)
+		(2, 8)
 	}
 
-	fn dec_reg(&mut self, reg_name:registers::RegisterName) -> (u16, u64) {
-		let operand = match reg_name {
-			registers::RegisterName::a => self.reg.a,
-			registers::RegisterName::b => self.reg.b,
-			registers::RegisterName::c => self.reg.c,
-			registers::RegisterName::d => self.reg.d,
-			registers::RegisterName::e => self.reg.e,
-			registers::RegisterName::h => self.reg.h,
-			registers::RegisterName::l => self.reg.l,			
-			_ => panic!("Invalid register in inc")
-		};
-		let (res, carry) = operand.overflowing_sub(1);
+	fn inc_8(&mut self, operand:u8) -> u8 {
+		let res = operand.wrapping_add(1);
 		let cf = self.reg.get_c();
-		let half = self.reg.e & 0x0F == 0x0F; // Check this!
-		self.reg.set_flags(carry, false, half, cf);
-		match reg_name {
-			registers::RegisterName::a => self.reg.a = res,
-			registers::RegisterName::b => self.reg.b = res,
-			registers::RegisterName::c => self.reg.c = res,
-			registers::RegisterName::d => self.reg.d = res,
-			registers::RegisterName::e => self.reg.e = res,
-			registers::RegisterName::h => self.reg.h = res,
-			registers::RegisterName::l => self.reg.l = res,			
-			_ => panic!("Invalid register in inc")
-		};
-		(1, 4)
+		self.reg.set_flags(res == 0, false, operand & 0x0F == 0x0F, cf);
+		res
 	}
 
-	fn dec_a(&mut self) -> (u16, u64) {
-		let half = self.reg.a & 0xF == 0;
-		let (res, _carry) = self.reg.a.overflowing_sub(1);
-		self.reg.a = res;
-		let zero = self.reg.a == 0;
-		let carry = self.reg.get_c();
-		self.reg.set_flags(zero, true, half, carry);
-		(1, 4)
-	}
-
-	fn dec_b(&mut self) -> (u16, u64) {
-		let half = self.reg.b & 0xF == 0;
-		let (res, _carry) = self.reg.b.overflowing_sub(1);
-		self.reg.b = res;
-		let zero = self.reg.b == 0;
-		let carry = self.reg.get_c();
-		self.reg.set_flags(zero, true, half, carry);
-		(1, 4)
-	}
-
-	fn dec_c(&mut self) -> (u16, u64) {
-		let half = self.reg.c & 0xF == 0;
-		let (res, _carry) = self.reg.c.overflowing_sub(1);
-		self.reg.c = res;
-		let zero = self.reg.c == 0;
-		let carry = self.reg.get_c();
-		self.reg.set_flags(zero, true, half, carry);
-		(1, 4)
-	}
-
-	fn dec_d(&mut self) -> (u16, u64) {
-		let half = self.reg.d & 0xF == 0;
-		let (res, _carry) = self.reg.d.overflowing_sub(1);
-		self.reg.d = res;
-		let zero = self.reg.d == 0;
-		let carry = self.reg.get_c();
-		self.reg.set_flags(zero, true, half, carry);
-		(1, 4)
-	}
-	
-	fn dec_e(&mut self) -> (u16, u64) {
-		let half = self.reg.e & 0xF == 0;
-		let (res, _carry) = self.reg.e.overflowing_sub(1);
-		self.reg.e = res;
-		let zero = self.reg.e == 0;
-		let carry = self.reg.get_c();
-		self.reg.set_flags(zero, true, half, carry);
-		(1, 4)
-	}
-
-	fn dec_h(&mut self) -> (u16, u64) {
-		let half = self.reg.h & 0xF == 0;
-		let (res, _carry) = self.reg.h.overflowing_sub(1);
-		self.reg.h = res;
-		let zero = self.reg.h == 0;
-		let carry = self.reg.get_c();
-		self.reg.set_flags(zero, true, half, carry);
-		(1, 4)
-	}
-
-	fn dec_l(&mut self) -> (u16, u64) {
-		let half = self.reg.l & 0xF == 0;
-		let (res, _carry) = self.reg.h.overflowing_sub(1);
-		self.reg.l = res;
-		let zero = self.reg.l == 0;
-		let carry = self.reg.get_c();
-		self.reg.set_flags(zero, true, half, carry);
-		(1, 4)
-	}
-
-	fn subc_a(&mut self, val:u8) {
-		let hc = check_sub_half_carry(self.reg.a, (self.reg.get_c() as u8) + val);
-		let (res, carry) = self.reg.a.overflowing_sub((self.reg.get_c() as u8) + val);
-		self.reg.a = res;
-		self.reg.set_flags(res == 0, true, hc, !carry);
-	}
-
-	fn sub_a(&mut self, val:u8) {
-		let hc = check_sub_half_carry(self.reg.a, val);
-		let (res, carry) = self.reg.a.overflowing_sub(val);
-		self.reg.a = res;
-		self.reg.set_flags(res == 0, true, hc, !carry);
-	}
-
-	// A + B -> A
-	fn op_80(&mut self) -> (u16, u64) {
-		let half = (self.reg.a & 0xF) + (self.reg.b & 0xF) > 0xF;
-		let (res, _carry) = self.reg.a.overflowing_add(self.reg.b);
-		self.reg.a = res;
-		let zero = self.reg.a == 0;
-		self.reg.set_flags(zero, false, half, _carry);
-		(1, 4)
-	}
-
-	// A + C -> A
-	fn op_81(&mut self) -> (u16, u64) {
-		let half = (self.reg.a & 0xF) + (self.reg.c & 0xF) > 0xF;
-		let (res, _carry) = self.reg.a.overflowing_add(self.reg.c);
-		self.reg.a = res;
-		let zero = self.reg.a == 0;
-		self.reg.set_flags(zero, false, half, _carry);
-		(1, 4)
-	}
-
-	// A + D -> A
-	fn op_82(&mut self) -> (u16, u64) {
-		let half = (self.reg.a & 0xF) + (self.reg.d & 0xF) > 0xF;
-		let (res, _carry) = self.reg.a.overflowing_add(self.reg.d);
-		self.reg.a = res;
-		let zero = self.reg.a == 0;
-		self.reg.set_flags(zero, false, half, _carry);
-		(1, 4)
-	}
-
-	// A + E -> A
-	fn op_83(&mut self) -> (u16, u64) {
-		let half = (self.reg.a & 0xF) + (self.reg.e & 0xF) > 0xF;
-		let (res, _carry) = self.reg.a.overflowing_add(self.reg.e);
-		self.reg.a = res;
-		let zero = self.reg.a == 0;
-		self.reg.set_flags(zero, false, half, _carry);
-		(1, 4)
-	}
-
-	// A + H -> A
-	fn op_84(&mut self) -> (u16, u64) {
-		let half = (self.reg.a & 0xF) + (self.reg.h & 0xF) > 0xF;
-		let (res, _carry) = self.reg.a.overflowing_add(self.reg.h);
-		self.reg.a = res;
-		let zero = self.reg.a == 0;
-		self.reg.set_flags(zero, false, half, _carry);
-		(1, 4)
+	fn dec_8(&mut self, operand:u8) -> u8 {
+		let res = operand.wrapping_sub(1);
+		let cf = self.reg.get_c();
+		self.reg.set_flags(res == 0, true, operand & 0x0F == 0x00, cf);
+		res
 	}
 
-	// A + L -> A
-	fn op_85(&mut self) -> (u16, u64) {
-		let half = (self.reg.a & 0xF) + (self.reg.l & 0xF) > 0xF;
-		let (res, _carry) = self.reg.a.overflowing_add(self.reg.l);
-		self.reg.a = res;
-		let zero = self.reg.a == 0;
-		self.reg.set_flags(zero, false, half, _carry);
+	fn inc_reg(&mut self, reg_name:RegisterName) -> (u16, u64) {
+		let operand = self.reg.get_by_name(reg_name);
+		let res = self.inc_8(operand);
+		self.reg.set_by_name(reg_name, res);
 		(1, 4)
 	}
 
-	// A + A -> A
-	fn op_87(&mut self) -> (u16, u64) {
-		let half = (self.reg.a & 0xF) + (self.reg.a & 0xF) > 0xF;
-		let (res, _carry) = self.reg.a.overflowing_add(self.reg.a);
-		self.reg.a = res;
-		let zero = self.reg.a == 0;
-		self.reg.set_flags(zero, false, half, _carry);
+	fn dec_reg(&mut self, reg_name:RegisterName) -> (u16, u64) {
+		let operand = self.reg.get_by_name(reg_name);
+		let res = self.dec_8(operand);
+		self.reg.set_by_name(reg_name, res);
 		(1, 4)
 	}
-
 }
 
 mod test {
+	// Places a program in work RAM and points PC at it so tests don't depend on a cartridge
+	#[cfg(test)]
+	pub(crate) fn core_with_program(program:&[u8]) -> super::Core {
+		let mut testcore = super::Core::new();
+		for (i, byte) in program.iter().enumerate() {
+			testcore.mem.set_mem(0xC000 + i as u16, *byte);
+		}
+		testcore.reg.pc = 0xC000;
+		testcore
+	}
+
 	#[test]
 	fn test_stack() {
 		let mut testcore = super::Core::new();
@@ -1384,6 +1583,7 @@ mod test {
 	}
 
 	#[test]
+	#[allow(clippy::bool_assert_comparison)]
 	fn test_sub_half_carry() {
 		use super::check_sub_half_carry;
 		assert_eq!(check_sub_half_carry(0b00000000, 0x00000000), false);
@@ -1392,4 +1592,631 @@ mod test {
 		assert_eq!(check_sub_half_carry(0b10000000, 0b00000001), true);
 		assert_eq!(check_sub_half_carry(0b10010000, 0b00000001), true);
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn test_add_half_carry() {
+		use super::check_add_half_carry;
+		assert!(!check_add_half_carry(0x00, 0x00));
+		assert!(!check_add_half_carry(0x07, 0x08));
+		assert!(check_add_half_carry(0x08, 0x08));
+		assert!(check_add_half_carry(0xFF, 0x01));
+	}
+
+	#[test]
+	fn test_ld_8() {
+		// LD B,#; LD C,B; LD (HL),C; LD A,(HL)
+		let mut testcore = core_with_program(&[0x06, 0x5A, 0x48, 0x71, 0x7E]);
+		testcore.reg.set_hl(0xD000);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.b, 0x5A);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.c, 0x5A);
+		testcore.step().unwrap();
+		assert_eq!(testcore.mem.get_mem(0xD000), 0x5A);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x5A);
+		assert_eq!(testcore.reg.pc, 0xC005);
+	}
+
+	#[test]
+	fn test_ld_16() {
+		// LD DE,##; LD (##),SP; LD SP,HL; LD (HL+),A; LD A,(HL-)
+		let mut testcore = core_with_program(&[0x11, 0x34, 0x12, 0x08, 0x00, 0xD0, 0xF9, 0x22, 0x3A]);
+		testcore.reg.set_hl(0xD100);
+		testcore.reg.a = 0x77;
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.get_de(), 0x1234);
+		testcore.step().unwrap();
+		assert_eq!(testcore.mem.get_mem(0xD000), 0xFE);
+		assert_eq!(testcore.mem.get_mem(0xD001), 0xFF);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.sp, 0xD100);
+		testcore.step().unwrap();
+		assert_eq!(testcore.mem.get_mem(0xD100), 0x77);
+		assert_eq!(testcore.reg.get_hl(), 0xD101);
+		testcore.reg.a = 0;
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.get_hl(), 0xD100);
+	}
+
+	#[test]
+	fn test_ldh() {
+		// LDH (#),A; LDH A,(C)
+		let mut testcore = core_with_program(&[0xE0, 0x85, 0xF2]);
+		testcore.reg.a = 0x3C;
+		testcore.step().unwrap();
+		assert_eq!(testcore.mem.get_mem(0xFF85), 0x3C);
+		testcore.reg.a = 0;
+		testcore.reg.c = 0x85;
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x3C);
+	}
+
+	#[test]
+	fn test_inc_dec_8() {
+		// INC B; DEC B; DEC B; INC (HL)
+		let mut testcore = core_with_program(&[0x04, 0x05, 0x05, 0x34]);
+		testcore.reg.b = 0x0F;
+		testcore.reg.set_c(true);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.b, 0x10);
+		assert!(testcore.reg.get_h());
+		assert!(testcore.reg.get_c());
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.b, 0x0F);
+		assert!(testcore.reg.get_n());
+		assert!(testcore.reg.get_h());
+		testcore.reg.b = 0x01;
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.b, 0x00);
+		assert!(testcore.reg.get_z());
+		assert!(!testcore.reg.get_h());
+		testcore.reg.set_hl(0xD000);
+		testcore.mem.set_mem(0xD000, 0xFF);
+		testcore.step().unwrap();
+		assert_eq!(testcore.mem.get_mem(0xD000), 0x00);
+		assert!(testcore.reg.get_z());
+	}
+
+	#[test]
+	fn test_inc_dec_16() {
+		// INC BC; DEC DE; INC SP
+		let mut testcore = core_with_program(&[0x03, 0x1B, 0x33]);
+		testcore.reg.set_bc(0xFFFF);
+		testcore.reg.set_de(0x0000);
+		testcore.reg.set_flags(false, false, false, false);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.get_bc(), 0x0000);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.get_de(), 0xFFFF);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.sp, 0xFFFF);
+		assert_eq!(testcore.reg.f, 0x00);
+	}
+
+	#[test]
+	fn test_add_adc() {
+		// ADD A,B; ADC A,#
+		let mut testcore = core_with_program(&[0x80, 0xCE, 0x01]);
+		testcore.reg.a = 0xF8;
+		testcore.reg.b = 0x08;
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x00);
+		assert_eq!(testcore.reg.f, 0xB0);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x02);
+		assert_eq!(testcore.reg.f, 0x00);
+	}
+
+	#[test]
+	fn test_sub_sbc() {
+		// SUB A,C; SBC A,(HL)
+		let mut testcore = core_with_program(&[0x91, 0x9E]);
+		testcore.reg.a = 0x10;
+		testcore.reg.c = 0x20;
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0xF0);
+		assert_eq!(testcore.reg.f, 0x50);
+		testcore.reg.set_hl(0xD000);
+		testcore.mem.set_mem(0xD000, 0xEF);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x00);
+		assert_eq!(testcore.reg.f, 0xE0);
+	}
+
+	#[test]
+	fn test_logic_cp() {
+		// AND A,D; XOR A,#; OR A,E; CP A,#; CP A,B
+		let mut testcore = core_with_program(&[0xA2, 0xEE, 0x0F, 0xB3, 0xFE, 0x0F, 0xB8]);
+		testcore.reg.a = 0xF0;
+		testcore.reg.d = 0x0F;
+		testcore.reg.e = 0x0F;
+		testcore.reg.b = 0x10;
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x00);
+		assert_eq!(testcore.reg.f, 0xA0);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x0F);
+		assert_eq!(testcore.reg.f, 0x00);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x0F);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x0F);
+		assert_eq!(testcore.reg.f, 0xC0);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.f, 0x50);
+	}
+
+	#[test]
+	fn test_rotate_a() {
+		// RLCA; RRCA; RLA; RRA
+		let mut testcore = core_with_program(&[0x07, 0x0F, 0x17, 0x1F]);
+		testcore.reg.a = 0x80;
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x01);
+		assert_eq!(testcore.reg.f, 0x10);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x80);
+		assert_eq!(testcore.reg.f, 0x10);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x01);
+		assert_eq!(testcore.reg.f, 0x10);
+		testcore.reg.a = 0x00;
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x80);
+		assert_eq!(testcore.reg.f, 0x00);
+	}
+
+	#[test]
+	fn test_misc_flags() {
+		// DAA; CPL; SCF; CCF
+		let mut testcore = core_with_program(&[0x27, 0x2F, 0x37, 0x3F]);
+		testcore.reg.a = 0x15 + 0x27;
+		testcore.reg.set_flags(false, false, false, false);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x42);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0xBD);
+		assert_eq!(testcore.reg.f, 0x60);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.f, 0x10);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.f, 0x00);
+	}
+
+	#[test]
+	fn test_jumps() {
+		// JR +2; (skipped); JR NZ -4 not taken; JP ##
+		let mut testcore = core_with_program(&[0x18, 0x02, 0x00, 0x00, 0x20, 0xFC, 0xC3, 0x00, 0xD0]);
+		testcore.reg.set_z(true);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.pc, 0xC004);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.pc, 0xC006);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.pc, 0xD000);
+	}
+
+	#[test]
+	fn test_call_ret_rst() {
+		// CALL ##; ...; RST 08 at the target; RET at 0xC010
+		let mut testcore = core_with_program(&[0xCD, 0x10, 0xC0]);
+		testcore.mem.set_mem(0xC010, 0xC9);
+		testcore.reg.sp = 0xDFFE;
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.pc, 0xC010);
+		assert_eq!(testcore.reg.sp, 0xDFFC);
+		assert_eq!(testcore.mem.get_mem(0xDFFC), 0x03);
+		assert_eq!(testcore.mem.get_mem(0xDFFD), 0xC0);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.pc, 0xC003);
+		assert_eq!(testcore.reg.sp, 0xDFFE);
+
+		testcore.mem.set_mem(0xC003, 0xCF);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.pc, 0x0008);
+		assert_eq!(testcore.mem.get_mem(0xDFFC), 0x04);
+	}
+
+	#[test]
+	fn test_push_pop() {
+		// PUSH BC; POP AF
+		let mut testcore = core_with_program(&[0xC5, 0xF1]);
+		testcore.reg.set_bc(0x12FF);
+		testcore.step().unwrap();
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.get_af(), 0x12F0);
+		assert_eq!(testcore.reg.sp, 0xFFFE);
+	}
+
+	#[test]
+	fn test_add_hl() {
+		// ADD HL,DE
+		let mut testcore = core_with_program(&[0x19]);
+		testcore.reg.set_hl(0x8FFF);
+		testcore.reg.set_de(0x7001);
+		testcore.reg.set_flags(true, true, false, false);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.get_hl(), 0x0000);
+		assert_eq!(testcore.reg.f, 0xB0);
+	}
+
+	#[test]
+	fn test_sp_offset() {
+		// ADD SP,-1; LD HL,SP+1
+		let mut testcore = core_with_program(&[0xE8, 0xFF, 0xF8, 0x01]);
+		testcore.reg.sp = 0xD000;
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.sp, 0xCFFF);
+		assert_eq!(testcore.reg.f, 0x00);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.get_hl(), 0xD000);
+		assert_eq!(testcore.reg.f, 0x30);
+	}
+
+	#[test]
+	fn test_halt() {
+		let mut testcore = core_with_program(&[0x76, 0x00]);
+		testcore.step().unwrap();
+		assert_eq!(testcore.state, super::RunState::Halted);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.pc, 0xC001);
+	}
+
+	#[test]
+	fn test_cb_rotate() {
+		// RLC B; RRC C; RL D; RR E
+		let mut testcore = core_with_program(&[0xCB, 0x00, 0xCB, 0x09, 0xCB, 0x12, 0xCB, 0x1B]);
+		testcore.reg.b = 0x85;
+		testcore.reg.c = 0x01;
+		testcore.reg.d = 0x80;
+		testcore.reg.e = 0x00;
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.b, 0x0B);
+		assert_eq!(testcore.reg.f, 0x10);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.c, 0x80);
+		assert_eq!(testcore.reg.f, 0x10);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.d, 0x01);
+		assert_eq!(testcore.reg.f, 0x10);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.e, 0x80);
+		assert_eq!(testcore.reg.f, 0x00);
+	}
+
+	#[test]
+	fn test_cb_shift_swap() {
+		// SLA H; SRA L; SWAP A; SRL A
+		let mut testcore = core_with_program(&[0xCB, 0x24, 0xCB, 0x2D, 0xCB, 0x37, 0xCB, 0x3F]);
+		testcore.reg.h = 0x80;
+		testcore.reg.l = 0x81;
+		testcore.reg.a = 0x1F;
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.h, 0x00);
+		assert_eq!(testcore.reg.f, 0x90);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.l, 0xC0);
+		assert_eq!(testcore.reg.f, 0x10);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0xF1);
+		assert_eq!(testcore.reg.f, 0x00);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x78);
+		assert_eq!(testcore.reg.f, 0x10);
+	}
+
+	#[test]
+	fn test_cb_bit_res_set() {
+		// BIT 7,A; BIT 0,A; RES 0,A; SET 7,B
+		let mut testcore = core_with_program(&[0xCB, 0x7F, 0xCB, 0x47, 0xCB, 0x87, 0xCB, 0xF8]);
+		testcore.reg.a = 0x01;
+		testcore.reg.b = 0x00;
+		testcore.reg.set_flags(false, true, false, true);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.f, 0xB0);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.f, 0x30);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x00);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.b, 0x80);
+		assert_eq!(testcore.reg.pc, 0xC008);
+	}
+
+	#[test]
+	fn test_cb_hl() {
+		// SET 3,(HL); BIT 3,(HL); SWAP (HL)
+		let mut testcore = super::Core::new();
+		testcore.reg.set_hl(0xD000);
+		testcore.mem.set_mem(0xD000, 0x10);
+		assert_eq!(testcore.handle_cb(0xDE), (2, 16));
+		assert_eq!(testcore.mem.get_mem(0xD000), 0x18);
+		assert_eq!(testcore.handle_cb(0x5E), (2, 12));
+		assert!(!testcore.reg.get_z());
+		assert_eq!(testcore.handle_cb(0x36), (2, 16));
+		assert_eq!(testcore.mem.get_mem(0xD000), 0x81);
+	}
+
+	#[test]
+	fn test_interrupt_dispatch() {
+		use super::interrupts::Interrupt;
+		let mut testcore = core_with_program(&[0x00]);
+		testcore.mem.int.toggle(true);
+		testcore.mem.int.iflag = 0;
+		testcore.mem.int.ie = 0x1F;
+		testcore.mem.int.request(Interrupt::Timer);
+		testcore.mem.int.request(Interrupt::Serial);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.pc, 0x50);
+		assert!(!testcore.mem.int.enabled);
+		assert_eq!(testcore.mem.int.iflag, Interrupt::Serial.bit());
+		assert_eq!(testcore.pop(), 0xC000);
+	}
+
+	#[test]
+	fn test_interrupt_masked() {
+		use super::interrupts::Interrupt;
+		let mut testcore = core_with_program(&[0x00]);
+		testcore.mem.int.toggle(true);
+		testcore.mem.int.ie = Interrupt::VBlank.bit();
+		testcore.mem.int.iflag = 0;
+		testcore.mem.int.request(Interrupt::Joypad);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.pc, 0xC001);
+	}
+
+	#[test]
+	fn test_ei_delay() {
+		use super::interrupts::Interrupt;
+		// EI; NOP; NOP
+		let mut testcore = core_with_program(&[0xFB, 0x00, 0x00]);
+		testcore.mem.int.ie = 0x1F;
+		testcore.mem.int.request(Interrupt::VBlank);
+		testcore.step().unwrap();
+		assert!(!testcore.mem.int.enabled);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.pc, 0xC002);
+		assert!(testcore.mem.int.enabled);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.pc, 0x40);
+	}
+
+	#[test]
+	fn test_ei_di() {
+		// EI; DI; NOP
+		let mut testcore = core_with_program(&[0xFB, 0xF3, 0x00]);
+		testcore.step().unwrap();
+		testcore.step().unwrap();
+		testcore.step().unwrap();
+		assert!(!testcore.mem.int.enabled);
+	}
+
+	#[test]
+	fn test_reti() {
+		// RETI at 0xC000, returning to 0xC100
+		let mut testcore = core_with_program(&[0xD9]);
+		testcore.push(0xC100);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.pc, 0xC100);
+		assert!(testcore.mem.int.enabled);
+	}
+
+	#[test]
+	fn test_halt_wake() {
+		use super::interrupts::Interrupt;
+		// HALT; NOP
+		let mut testcore = core_with_program(&[0x76, 0x00]);
+		testcore.mem.int.ie = Interrupt::Timer.bit();
+		testcore.step().unwrap();
+		testcore.step().unwrap();
+		assert_eq!(testcore.state, super::RunState::Halted);
+		testcore.mem.int.request(Interrupt::Timer);
+		testcore.step().unwrap();
+		assert_eq!(testcore.state, super::RunState::Running);
+		assert_eq!(testcore.reg.pc, 0xC002);
+	}
+
+	#[test]
+	fn test_halt_interrupt() {
+		use super::interrupts::Interrupt;
+		// HALT with IME set jumps to the handler once woken
+		let mut testcore = core_with_program(&[0x76, 0x00]);
+		testcore.mem.int.toggle(true);
+		testcore.mem.int.ie = Interrupt::Timer.bit();
+		testcore.mem.int.iflag = 0;
+		testcore.step().unwrap();
+		testcore.step().unwrap();
+		testcore.mem.int.request(Interrupt::Timer);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.pc, 0x50);
+		assert_eq!(testcore.pop(), 0xC001);
+	}
+
+	#[test]
+	fn test_halt_bug() {
+		use super::interrupts::Interrupt;
+		// HALT; LD A,#14 executes as LD A,#3E then INC D
+		let mut testcore = core_with_program(&[0x76, 0x3E, 0x14]);
+		testcore.mem.int.ie = Interrupt::VBlank.bit();
+		testcore.mem.int.request(Interrupt::VBlank);
+		testcore.reg.d = 0;
+		testcore.step().unwrap();
+		assert_eq!(testcore.state, super::RunState::Running);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x3E);
+		assert_eq!(testcore.reg.pc, 0xC002);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.d, 0x01);
+	}
+
+	#[test]
+	fn test_stop() {
+		use super::interrupts::Interrupt;
+		// STOP; NOP
+		let mut testcore = core_with_program(&[0x10, 0x00, 0x00]);
+		testcore.mem.int.iflag = 0;
+		testcore.step().unwrap();
+		assert_eq!(testcore.state, super::RunState::Stopped);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.pc, 0xC002);
+		testcore.mem.int.request(Interrupt::Joypad);
+		testcore.step().unwrap();
+		assert_eq!(testcore.state, super::RunState::Running);
+		assert_eq!(testcore.reg.pc, 0xC003);
+	}
+
+	#[test]
+	fn test_speed_switch() {
+		// LD A,#01; LDH (4D),A; STOP
+		let mut testcore = core_with_program(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00]);
+		testcore.step().unwrap();
+		testcore.step().unwrap();
+		assert_eq!(testcore.mem.get_mem(0xFF4D), 0x7F);
+		testcore.step().unwrap();
+		assert_eq!(testcore.state, super::RunState::Running);
+		assert_eq!(testcore.mem.get_mem(0xFF4D), 0xFE);
+	}
+
+	#[test]
+	fn test_timer_interrupt() {
+		// LD A,#05; LDH (07),A; LD A,#FF; LDH (05),A; then NOPs
+		let mut testcore = core_with_program(&[0x3E, 0x05, 0xE0, 0x07, 0x3E, 0xFF, 0xE0, 0x05]);
+		testcore.mem.int.toggle(true);
+		testcore.mem.int.ie = super::Interrupt::Timer.bit();
+		testcore.mem.int.iflag = 0;
+		for _ in 0 .. 16 {
+			testcore.step().unwrap();
+			if testcore.reg.pc == 0x50 {
+				break;
+			}
+		}
+		assert_eq!(testcore.reg.pc, 0x50);
+	}
+
+	#[test]
+	fn test_joypad() {
+		use super::joypad::Button;
+		// LD A,#10; LDH (00),A; STOP, then LDH A,(00) once woken
+		let mut testcore = core_with_program(&[0x3E, 0x10, 0xE0, 0x00, 0x10, 0x00, 0xF0, 0x00]);
+		testcore.mem.int.iflag = 0;
+		testcore.step().unwrap();
+		testcore.step().unwrap();
+		testcore.step().unwrap();
+		assert_eq!(testcore.state, super::RunState::Stopped);
+		testcore.press(Button::A);
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0xDE);
+		testcore.release(Button::A);
+		assert_eq!(testcore.mem.get_mem(0xFF00), 0xDF);
+	}
+
+	#[test]
+	fn test_illegal_opcode() {
+		use super::error::EmuError;
+		let mut testcore = core_with_program(&[0x00, 0xD3]);
+		testcore.step().unwrap();
+		testcore.step().unwrap();
+		assert_eq!(testcore.state, super::RunState::Locked { opcode: 0xD3, pc: 0xC001 });
+		match testcore.status() {
+			Err(EmuError::IllegalOpcode { opcode: 0xD3, pc: 0xC001 }) => {}
+			_ => panic!("Expected an illegal opcode error"),
+		}
+	}
+
+	#[test]
+	fn test_lock_up() {
+		// EI then an undefined opcode, with a timer interrupt already pending
+		let mut testcore = core_with_program(&[0xFB, 0xFD, 0x00]);
+		testcore.mem.int.ie = 0x04;
+		testcore.mem.int.iflag = 0x04;
+		testcore.step().unwrap();
+		testcore.step().unwrap();
+		let div = testcore.mem.get_mem(0xFF04);
+		let ly = testcore.mem.disp.ly_coord;
+		for _ in 0 .. 0x100 {
+			testcore.step().unwrap();
+		}
+		// Interrupts aren't serviced and the PC never moves, but peripherals carry on
+		assert_eq!(testcore.reg.pc, 0xC001);
+		assert_eq!(testcore.mem.get_mem(0xFF04), div.wrapping_add(4));
+		assert!(testcore.mem.disp.ly_coord != ly);
+		assert!(testcore.status().is_err());
+	}
+
+	#[test]
+	fn test_trace() {
+		use std::io;
+		use std::rc::Rc;
+		use std::cell::RefCell;
+		// Shares the written bytes with the test after the core takes ownership of the writer
+		struct Shared(Rc<RefCell<Vec<u8>>>);
+		impl io::Write for Shared {
+			fn write(&mut self, buf:&[u8]) -> io::Result<usize> {
+				self.0.borrow_mut().extend_from_slice(buf);
+				Ok(buf.len())
+			}
+			fn flush(&mut self) -> io::Result<()> {
+				Ok(())
+			}
+		}
+		// NOP; LDH A,(44)
+		let mut testcore = core_with_program(&[0x00, 0xF0, 0x44]);
+		let out = Rc::new(RefCell::new(Vec::new()));
+		testcore.trace_to(Box::new(Shared(out.clone())), true);
+		testcore.step().unwrap();
+		testcore.step().unwrap();
+		assert_eq!(testcore.reg.a, 0x90);
+		let text = String::from_utf8(out.borrow().clone()).unwrap();
+		let lines: Vec<&str> = text.lines().collect();
+		assert_eq!(lines.len(), 2);
+		assert_eq!(lines[0], "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:C000 PCMEM:00,F0,44,00");
+		assert!(lines[1].ends_with("PC:C001 PCMEM:F0,44,00,00"));
+	}
+
+	#[test]
+	fn test_watchpoint() {
+		use super::watch::{Watchpoint, WatchKind};
+		// LD A,#42; LD (C100),A; LD (C100),A; LD A,(C100)
+		let mut testcore = core_with_program(&[0x3E, 0x42, 0xEA, 0x00, 0xC1, 0xEA, 0x00, 0xC1, 0xFA, 0x00, 0xC1]);
+		testcore.mem.watchpoints.push(Watchpoint { start: 0xC100, end: 0xC100, kind: WatchKind::Change });
+		testcore.mem.watchpoints.push(Watchpoint { start: 0xC0F0, end: 0xC1FF, kind: WatchKind::Read });
+		testcore.step().unwrap();
+		assert_eq!(testcore.mem.take_watch_hit(), None);
+		testcore.step().unwrap();
+		match testcore.mem.take_watch_hit() {
+			Some(hit) => {
+				assert_eq!(hit.index, 0);
+				assert_eq!(hit.pc, 0xC002);
+				assert_eq!((hit.old, hit.new), (0x00, 0x42));
+			}
+			_ => panic!("Expected a watchpoint hit"),
+		}
+		// The write completed and the CPU moved on before stopping
+		assert_eq!(testcore.reg.pc, 0xC005);
+		// Writing the same value again isn't a change
+		testcore.step().unwrap();
+		assert_eq!(testcore.mem.take_watch_hit(), None);
+		testcore.step().unwrap();
+		match testcore.mem.take_watch_hit() {
+			Some(hit) => assert_eq!((hit.index, hit.addr, hit.pc), (1, 0xC100, 0xC008)),
+			_ => panic!("Expected a watchpoint hit"),
+		}
+		// Reads from outside an instruction, like a debugger's, don't trip anything
+		testcore.mem.get_mem(0xC100);
+		assert!(testcore.mem.watch_hit.get().is_none());
+	}
+
+	#[test]
+	fn test_read_watch_skips_fetches() {
+		use super::watch::{Watchpoint, WatchKind};
+		// NOP; LD A,#12; LD A,(C001)
+		let mut testcore = core_with_program(&[0x00, 0x3E, 0x12, 0xFA, 0x01, 0xC0]);
+		testcore.mem.watchpoints.push(Watchpoint { start: 0xC000, end: 0xC005, kind: WatchKind::Read });
+		testcore.step().unwrap();
+		testcore.step().unwrap();
+		assert_eq!(testcore.mem.take_watch_hit(), None);
+		testcore.step().unwrap();
+		assert_eq!(testcore.mem.take_watch_hit().unwrap().addr, 0xC001);
+	}
+}
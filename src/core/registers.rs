@@ -1,3 +1,7 @@
+use std::fmt;
+
+#[allow(non_camel_case_types, dead_code)]
+#[derive(Clone, Copy)]
 pub enum RegisterName {
 	a,
 	f,
@@ -9,6 +13,10 @@ pub enum RegisterName {
 	l
 }
 
+// Names read_named and write_named accept, the pairs in the order debuggers usually list them
+pub const BYTE_NAMES: [&str; 8] = ["a", "f", "b", "c", "d", "e", "h", "l"];
+pub const WORD_NAMES: [&str; 6] = ["af", "bc", "de", "hl", "sp", "pc"];
+
 pub struct Registers {
 	pub a: u8,
 	pub f: u8,
@@ -78,6 +86,79 @@ impl Registers {
 		self.l = (hl & 0x00FF) as u8;
 	}
 
+	pub fn get_by_name(&self, name:RegisterName) -> u8 {
+		match name {
+			RegisterName::a => self.a,
+			RegisterName::f => self.f,
+			RegisterName::b => self.b,
+			RegisterName::c => self.c,
+			RegisterName::d => self.d,
+			RegisterName::e => self.e,
+			RegisterName::h => self.h,
+			RegisterName::l => self.l,
+		}
+	}
+
+	pub fn set_by_name(&mut self, name:RegisterName, val:u8) {
+		match name {
+			RegisterName::a => self.a = val,
+			RegisterName::f => self.f = val & 0xF0,
+			RegisterName::b => self.b = val,
+			RegisterName::c => self.c = val,
+			RegisterName::d => self.d = val,
+			RegisterName::e => self.e = val,
+			RegisterName::h => self.h = val,
+			RegisterName::l => self.l = val,
+		}
+	}
+
+	pub fn is_name(name:&str) -> bool {
+		BYTE_NAMES.contains(&name) || WORD_NAMES.contains(&name)
+	}
+
+	// Any 8 or 16-bit register by its lowercase name, for debuggers
+	pub fn read_named(&self, name:&str) -> Option<u16> {
+		match name {
+			"a" => Some(self.a as u16),
+			"f" => Some(self.f as u16),
+			"b" => Some(self.b as u16),
+			"c" => Some(self.c as u16),
+			"d" => Some(self.d as u16),
+			"e" => Some(self.e as u16),
+			"h" => Some(self.h as u16),
+			"l" => Some(self.l as u16),
+			"af" => Some(self.get_af()),
+			"bc" => Some(self.get_bc()),
+			"de" => Some(self.get_de()),
+			"hl" => Some(self.get_hl()),
+			"sp" => Some(self.sp),
+			"pc" => Some(self.pc),
+			_ => None,
+		}
+	}
+
+	// Returns false for an unknown name, 8-bit registers take the low byte of val
+	pub fn write_named(&mut self, name:&str, val:u16) -> bool {
+		match name {
+			"a" => self.a = val as u8,
+			"f" => self.f = val as u8 & 0xF0,
+			"b" => self.b = val as u8,
+			"c" => self.c = val as u8,
+			"d" => self.d = val as u8,
+			"e" => self.e = val as u8,
+			"h" => self.h = val as u8,
+			"l" => self.l = val as u8,
+			"af" => self.set_af(val & 0xFFF0),
+			"bc" => self.set_bc(val),
+			"de" => self.set_de(val),
+			"hl" => self.set_hl(val),
+			"sp" => self.sp = val,
+			"pc" => self.pc = val,
+			_ => return false,
+		}
+		true
+	}
+
 	pub fn set_flags(&mut self, z:bool, n:bool, h:bool, c:bool) {
 		self.f = ((z as u8) << 7) + ((n as u8) << 6)
 			+ ((h as u8) << 5) + ((c as u8) << 4);
@@ -114,19 +195,17 @@ impl Registers {
 	pub fn set_c(&mut self, c:bool) {
 		self.f = (self.f & 0b11101111) | ((c as u8) << 4);
 	}
+}
 
-	pub fn disp_state(&self) {
-		println!("AF: {:4X}", self.get_af());
-		println!("BC: {:4X}", self.get_bc());
-		println!("DE: {:4X}", self.get_de());
-		println!("HL: {:4X}", self.get_hl());
-		println!("PC: {:4X}", self.pc);
-		println!("SP: {:4X}", self.sp);
-		println!("Z:{:?} N:{:?} H:{:?} C:{:?}", self.get_z(), self.get_n(), self.get_h(), self.get_c());
+impl fmt::Display for Registers {
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		write!(f, "AF:{:04X} BC:{:04X} DE:{:04X} HL:{:04X} SP:{:04X} PC:{:04X} Z:{} N:{} H:{} C:{}",
+			self.get_af(), self.get_bc(), self.get_de(), self.get_hl(), self.sp, self.pc,
+			self.get_z() as u8, self.get_n() as u8, self.get_h() as u8, self.get_c() as u8)
 	}
-
 }
 
+#[allow(clippy::bool_assert_comparison)]
 mod test {
 	#[test]
 	fn test_af() {
@@ -233,4 +312,19 @@ mod test {
 		assert_eq!(testreg.get_h(), false);
 		assert_eq!(testreg.get_c(), false);
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn test_named() {
+		let mut reg = super::Registers::load_defaults();
+		assert_eq!(reg.read_named("hl"), Some(0x014D));
+		assert_eq!(reg.read_named("e"), Some(0xD8));
+		assert!(reg.write_named("de", 0x1234));
+		assert_eq!(reg.d, 0x12);
+		assert!(reg.write_named("af", 0xFFFF));
+		assert_eq!(reg.get_af(), 0xFFF0);
+		assert!(!reg.write_named("ix", 0));
+		assert_eq!(reg.read_named("ix"), None);
+		assert!(super::Registers::is_name("sp"));
+		assert!(!super::Registers::is_name("ix"));
+	}
+}
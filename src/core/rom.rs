@@ -1,28 +1,289 @@
+use std::fs;
 use std::fs::File;
-use std::io::Read;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
+use super::error::EmuError;
+use super::header::{CartridgeHeader, HeaderMismatch};
+use super::mbc::{Mbc, MBC2_RAM_SIZE};
+use super::rtc;
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct ROM {
-	pub data: [u8; 0x8000],
-	pub r_type: u8
+	pub data: Vec<u8>,
+	pub ram: Vec<u8>, // External cartridge RAM at 0xA000-0xBFFF
+	pub header: CartridgeHeader,
+	pub mbc: Mbc,
+	pub save_path: Option<PathBuf>, // Where battery-backed RAM persists, next to the ROM
+	pub ram_dirty: bool,
 }
 
+// The largest size code in the header, 0x08, is 8 MiB
+const MAX_ROM_SIZE: usize = 0x800000;
+
 impl ROM {
 	pub fn create_rom() -> ROM {
+		let data = vec![0; 0x8000];
 		ROM{
-			data: [0; 0x8000],
-			r_type: 0
+			header: CartridgeHeader::parse(&data),
+			data,
+			ram: Vec::new(),
+			mbc: Mbc::NoMbc,
+			save_path: None,
+			ram_dirty: false
 		}
 	}
 
-	pub fn load_file(&mut self, filename:String) {
-		let mut fo: File = match File::open(filename.clone()) {
-			Ok(file) => file,
-            Err(_) => panic!("Can't read {}", filename)
+	pub fn load_file(&mut self, filename:String) -> Result<(), EmuError> {
+		let mut data = Vec::new();
+		File::open(&filename)?.read_to_end(&mut data)?;
+		self.load_data(data)?;
+		if self.header.has_battery() {
+			let path = Path::new(&filename).with_extension("sav");
+			match File::open(&path) {
+				Ok(mut save) => {
+					let mut saved = Vec::new();
+					save.read_to_end(&mut saved)?;
+					self.load_battery_data(&saved);
+				}
+				// No save yet is the normal first run
+				Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+				Err(e) => return Err(EmuError::IoError(e)),
+			}
+			self.save_path = Some(path);
+		}
+		Ok(())
+	}
+
+	// Writes the save file if RAM or the clock registers changed since the last flush.
+	// Frontends call this now and then, it's too slow for the emulation loop.
+	pub fn flush(&mut self) -> io::Result<()> {
+		let rtc_dirty = match self.mbc.rtc() {
+			Some(rtc) => rtc.dirty,
+			None => false,
+		};
+		if !self.ram_dirty && !rtc_dirty {
+			return Ok(());
+		}
+		let path = match self.save_path {
+			Some(ref path) => path.clone(),
+			None => return Ok(()),
 		};
-    	fo.read(&mut self.data);
+		let data = self.battery_data();
+		// Write alongside and rename over the save, so a crash mid-write leaves the old one intact
+		let temp = path.with_extension("sav.tmp");
+		let mut file = File::create(&temp)?;
+		file.write_all(&data)?;
+		file.sync_all()?;
+		fs::rename(&temp, &path)?;
+		self.ram_dirty = false;
+		if let Some(rtc) = self.mbc.rtc() {
+			rtc.dirty = false;
+		}
+		Ok(())
+	}
+
+	pub fn load_data(&mut self, mut data:Vec<u8>) -> Result<(), EmuError> {
+		if data.len() > MAX_ROM_SIZE {
+			return Err(EmuError::RomTooLarge(data.len()));
+		}
+		if data.len() < 0x0150 {
+			return Err(EmuError::RomTooSmall(data.len()));
+		}
+		let header = CartridgeHeader::parse(&data);
+		let fatal: Vec<HeaderMismatch> = header.validate(&data).into_iter()
+			.filter(|m| m.is_fatal())
+			.collect();
+		if !fatal.is_empty() {
+			return Err(EmuError::BadHeader(fatal));
+		}
+		if data.len() < 0x8000 {
+			data.resize(0x8000, 0);
+		}
+		self.header = header;
+		self.mbc = Mbc::from_type(self.header.cart_type, &data);
+		let ram_len = if self.mbc.is_mbc2() { MBC2_RAM_SIZE } else { self.header.ram_bytes() };
+		self.ram = vec![0; ram_len];
+		self.data = data;
+		Ok(())
+	}
+
+	pub fn validate(&self) -> Vec<HeaderMismatch> {
+		self.header.validate(&self.data)
 	}
 
 	pub fn get_mem(&self, loc:u16) -> u8 {
-		self.data[loc as usize]
+		self.mbc.read_rom(&self.data, loc)
+	}
+
+	pub fn set_mem(&mut self, loc:u16, val:u8) {
+		self.mbc.write_rom(loc, val);
 	}
-}
\ No newline at end of file
+
+	pub fn update(&mut self, cycles:u64) {
+		self.mbc.tick(cycles);
+	}
+
+	// External RAM followed by the RTC footer, in the layout other emulators save
+	pub fn battery_data(&mut self) -> Vec<u8> {
+		let mut data = self.ram.clone();
+		if let Some(rtc) = self.mbc.rtc() {
+			data.extend(rtc.save_bytes());
+		}
+		data
+	}
+
+	pub fn load_battery_data(&mut self, data:&[u8]) {
+		let len = self.ram.len().min(data.len());
+		self.ram[.. len].copy_from_slice(&data[.. len]);
+		if data.len() >= len + rtc::SAVE_SIZE - 4 {
+			if let Some(rtc) = self.mbc.rtc() {
+				rtc.load_bytes(&data[len ..]);
+			}
+		}
+	}
+
+	pub fn get_ram(&self, loc:u16) -> u8 {
+		self.mbc.read_ram(&self.ram, loc)
+	}
+
+	pub fn set_ram(&mut self, loc:u16, val:u8) {
+		// Writes to disabled or missing RAM are common and shouldn't cost a save
+		if self.mbc.write_ram(&mut self.ram, loc, val) {
+			self.ram_dirty = true;
+		}
+	}
+}
+
+mod test {
+	#[test]
+	fn test_load_mbc1() {
+		let mut rom = super::ROM::create_rom();
+		let mut data = vec![0; 0x10000];
+		data[0x0147] = 0x03;
+		data[0x0149] = 0x03;
+		data[0xC000] = 0x99;
+		rom.load_data(data).unwrap();
+		assert_eq!(rom.ram.len(), 0x8000);
+		rom.set_mem(0x2000, 0x03);
+		assert_eq!(rom.get_mem(0x4000), 0x99);
+		rom.set_mem(0x0000, 0x0A);
+		rom.set_ram(0xA123, 0x42);
+		assert_eq!(rom.get_ram(0xA123), 0x42);
+	}
+
+	#[test]
+	fn test_battery_data() {
+		use super::super::rtc::ClockSource;
+		let mut rom = super::ROM::create_rom();
+		let mut data = vec![0; 0x8000];
+		data[0x0147] = 0x10;
+		data[0x0149] = 0x02;
+		rom.load_data(data).unwrap();
+		rom.mbc.rtc().unwrap().set_source(ClockSource::Cycles);
+		rom.ram[0x10] = 0xAB;
+		rom.mbc.rtc().unwrap().hours = 7;
+		let saved = rom.battery_data();
+		assert_eq!(saved.len(), 0x2000 + 48);
+
+		let mut loaded = super::ROM::create_rom();
+		let mut data = vec![0; 0x8000];
+		data[0x0147] = 0x10;
+		data[0x0149] = 0x02;
+		loaded.load_data(data).unwrap();
+		loaded.mbc.rtc().unwrap().set_source(ClockSource::Cycles);
+		loaded.load_battery_data(&saved);
+		assert_eq!(loaded.ram[0x10], 0xAB);
+		assert_eq!(loaded.mbc.rtc().unwrap().hours, 7);
+	}
+
+	#[test]
+	fn test_load_mbc2() {
+		let mut rom = super::ROM::create_rom();
+		let mut data = vec![0; 0x8000];
+		data[0x0147] = 0x06;
+		rom.load_data(data).unwrap();
+		assert!(rom.mbc.is_mbc2());
+		assert_eq!(rom.ram.len(), 0x200);
+	}
+
+	#[test]
+	fn test_save_file() {
+		use std::env;
+		use std::fs;
+		let dir = env::temp_dir().join("rustboy_test_save_file");
+		let _ = fs::create_dir_all(&dir);
+		let rom_path = dir.join("game.gb");
+		let mut data = vec![0; 0x8000];
+		data[0x0147] = 0x03;
+		data[0x0149] = 0x02;
+		fs::write(&rom_path, &data).unwrap();
+		let _ = fs::remove_file(dir.join("game.sav"));
+
+		let mut rom = super::ROM::create_rom();
+		rom.load_file(rom_path.to_str().unwrap().to_string()).unwrap();
+		rom.set_mem(0x0000, 0x0A);
+		rom.set_ram(0xA010, 0x5A);
+		rom.flush().unwrap();
+		assert!(!rom.ram_dirty);
+		assert!(!dir.join("game.sav.tmp").exists());
+		assert_eq!(fs::read(dir.join("game.sav")).unwrap().len(), 0x2000);
+
+		let mut loaded = super::ROM::create_rom();
+		loaded.load_file(rom_path.to_str().unwrap().to_string()).unwrap();
+		assert_eq!(loaded.ram[0x10], 0x5A);
+		let _ = fs::remove_dir_all(&dir);
+	}
+
+	#[test]
+	fn test_rtc_save_file() {
+		use std::env;
+		use std::fs;
+		let dir = env::temp_dir().join("rustboy_test_rtc_save_file");
+		let _ = fs::create_dir_all(&dir);
+		let rom_path = dir.join("game.gb");
+		let mut data = vec![0; 0x8000];
+		data[0x0147] = 0x10;
+		data[0x0149] = 0x02;
+		fs::write(&rom_path, &data).unwrap();
+		let _ = fs::remove_file(dir.join("game.sav"));
+
+		let mut rom = super::ROM::create_rom();
+		rom.load_file(rom_path.to_str().unwrap().to_string()).unwrap();
+		// A running clock alone isn't worth a write, latching it is
+		rom.flush().unwrap();
+		assert!(!dir.join("game.sav").exists());
+		rom.set_mem(0x6000, 0x00);
+		rom.set_mem(0x6000, 0x01);
+		rom.flush().unwrap();
+		assert_eq!(fs::read(dir.join("game.sav")).unwrap().len(), 0x2000 + 48);
+		assert!(!rom.mbc.rtc().unwrap().dirty);
+		let _ = fs::remove_dir_all(&dir);
+	}
+
+	#[test]
+	fn test_load_errors() {
+		use super::super::error::EmuError;
+		use super::super::header::HeaderMismatch;
+		let mut rom = super::ROM::create_rom();
+		match rom.load_file("does/not/exist.gb".to_string()) {
+			Err(EmuError::IoError(_)) => {}
+			_ => panic!("Expected an I/O error"),
+		}
+		match rom.load_data(vec![0; 0x100]) {
+			Err(EmuError::RomTooSmall(0x100)) => {}
+			_ => panic!("Expected a too small error"),
+		}
+		match rom.load_data(vec![0; 0x1000000]) {
+			Err(EmuError::RomTooLarge(0x1000000)) => {}
+			_ => panic!("Expected a too large error"),
+		}
+		let mut data = vec![0; 0x8000];
+		data[0x0147] = 0xFC;
+		match rom.load_data(data) {
+			Err(EmuError::BadHeader(ref mismatches)) => assert_eq!(mismatches, &vec![HeaderMismatch::UnsupportedCartType(0xFC)]),
+			_ => panic!("Expected a bad header error"),
+		}
+	}
+}
@@ -0,0 +1,325 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const CYCLES_PER_SECOND: u64 = 4194304;
+// Size of the footer other emulators append to MBC3 save files
+pub const SAVE_SIZE: usize = 48;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockSource {
+	Host, // Follows the wall clock, including time the emulator was closed
+	Cycles, // Advances only with emulated cycles, for deterministic runs
+}
+
+pub struct Rtc {
+	pub seconds: u8,
+	pub minutes: u8,
+	pub hours: u8,
+	pub days: u16, // 9-bit day counter
+	pub halt: bool,
+	pub carry: bool, // Day counter overflowed
+	pub latched: [u8; 5], // Snapshot the CPU reads, taken on a 0 then 1 write to 0x6000-0x7FFF
+	pub latch_prev: u8,
+	pub source: ClockSource,
+	pub cycles: u64, // Cycles into the current second
+	pub host_time: u64, // Wall clock seconds the registers were last synced to
+	pub dirty: bool, // Latched or written since the save was last flushed
+}
+
+fn now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn read_u32(data:&[u8], at:usize) -> u32 {
+	(data[at] as u32) | ((data[at + 1] as u32) << 8) | ((data[at + 2] as u32) << 16) | ((data[at + 3] as u32) << 24)
+}
+
+impl Rtc {
+	pub fn create() -> Rtc {
+		Rtc {
+			seconds: 0,
+			minutes: 0,
+			hours: 0,
+			days: 0,
+			halt: false,
+			carry: false,
+			latched: [0; 5],
+			latch_prev: 0xFF,
+			source: ClockSource::Host,
+			cycles: 0,
+			host_time: now(),
+			dirty: false
+		}
+	}
+
+	pub fn set_source(&mut self, source:ClockSource) {
+		self.sync();
+		self.source = source;
+		self.cycles = 0;
+		self.host_time = now();
+	}
+
+	// Brings the counters up to date with the host clock
+	fn sync(&mut self) {
+		if self.source == ClockSource::Host {
+			let time = now();
+			if time > self.host_time {
+				self.advance(time - self.host_time);
+			}
+			self.host_time = time;
+		}
+	}
+
+	pub fn tick(&mut self, cycles:u64) {
+		if self.source != ClockSource::Cycles || self.halt {
+			return;
+		}
+		self.cycles += cycles;
+		let secs = self.cycles / CYCLES_PER_SECOND;
+		self.cycles %= CYCLES_PER_SECOND;
+		self.advance(secs);
+	}
+
+	fn in_range(&self) -> bool {
+		self.seconds < 60 && self.minutes < 60 && self.hours < 24
+	}
+
+	// Out of range values written by the game count up to their bit width before wrapping
+	fn tick_second(&mut self) {
+		self.seconds = (self.seconds + 1) & 0x3F;
+		if self.seconds != 60 {
+			return;
+		}
+		self.seconds = 0;
+		self.minutes = (self.minutes + 1) & 0x3F;
+		if self.minutes != 60 {
+			return;
+		}
+		self.minutes = 0;
+		self.hours = (self.hours + 1) & 0x1F;
+		if self.hours != 24 {
+			return;
+		}
+		self.hours = 0;
+		self.days += 1;
+		if self.days == 512 {
+			self.days = 0;
+			self.carry = true;
+		}
+	}
+
+	pub fn advance(&mut self, mut secs:u64) {
+		if self.halt {
+			return;
+		}
+		while secs > 0 && !self.in_range() {
+			self.tick_second();
+			secs -= 1;
+		}
+		if secs == 0 {
+			return;
+		}
+		let total = (self.days as u64) * 86400 + (self.hours as u64) * 3600
+			+ (self.minutes as u64) * 60 + (self.seconds as u64) + secs;
+		let days = total / 86400;
+		if days >= 512 {
+			self.carry = true;
+		}
+		self.days = (days % 512) as u16;
+		self.hours = ((total % 86400) / 3600) as u8;
+		self.minutes = ((total % 3600) / 60) as u8;
+		self.seconds = (total % 60) as u8;
+	}
+
+	fn registers(&self) -> [u8; 5] {
+		[
+			self.seconds,
+			self.minutes,
+			self.hours,
+			(self.days & 0xFF) as u8,
+			((self.days >> 8) as u8 & 0x01) | ((self.halt as u8) << 6) | ((self.carry as u8) << 7)
+		]
+	}
+
+	pub fn write_latch(&mut self, val:u8) {
+		if self.latch_prev == 0x00 && val == 0x01 {
+			self.sync();
+			self.latched = self.registers();
+			self.dirty = true;
+		}
+		self.latch_prev = val;
+	}
+
+	// reg is the 0x08-0x0C value selected through 0x4000-0x5FFF
+	pub fn read(&self, reg:u8) -> u8 {
+		self.latched[(reg - 0x08) as usize]
+	}
+
+	pub fn write(&mut self, reg:u8, val:u8) {
+		self.sync();
+		self.dirty = true;
+		match reg {
+			0x08 => {
+				self.seconds = val & 0x3F;
+				self.cycles = 0;
+			}
+			0x09 => self.minutes = val & 0x3F,
+			0x0A => self.hours = val & 0x1F,
+			0x0B => self.days = (self.days & 0x100) | val as u16,
+			_ => {
+				self.days = (self.days & 0xFF) | ((val as u16 & 0x01) << 8);
+				self.halt = val & 0x40 != 0;
+				self.carry = val & 0x80 != 0;
+			}
+		}
+		self.latched[(reg - 0x08) as usize] = self.registers()[(reg - 0x08) as usize];
+	}
+
+	// The 48 byte BGB/VBA-M layout: live and latched registers as u32s, then a u64 timestamp
+	pub fn save_bytes(&mut self) -> Vec<u8> {
+		self.sync();
+		// Stamp the save with the wall clock even in Cycles mode, whoever loads it next counts from here
+		self.host_time = now();
+		let mut data = Vec::with_capacity(SAVE_SIZE);
+		for val in self.registers().iter().chain(self.latched.iter()) {
+			data.extend_from_slice(&[*val, 0, 0, 0]);
+		}
+		for i in 0 .. 8 {
+			data.push((self.host_time >> (i * 8)) as u8);
+		}
+		data
+	}
+
+	pub fn load_bytes(&mut self, data:&[u8]) {
+		if data.len() < SAVE_SIZE - 4 {
+			return;
+		}
+		let regs: Vec<u8> = (0 .. 5).map(|i| read_u32(data, i * 4) as u8).collect();
+		self.seconds = regs[0] & 0x3F;
+		self.minutes = regs[1] & 0x3F;
+		self.hours = regs[2] & 0x1F;
+		self.days = regs[3] as u16 | ((regs[4] as u16 & 0x01) << 8);
+		self.halt = regs[4] & 0x40 != 0;
+		self.carry = regs[4] & 0x80 != 0;
+		for i in 0 .. 5 {
+			self.latched[i] = read_u32(data, 20 + i * 4) as u8;
+		}
+		// Some emulators write a 32-bit timestamp, giving a 44 byte footer
+		let saved = if data.len() >= SAVE_SIZE {
+			read_u32(data, 40) as u64 | ((read_u32(data, 44) as u64) << 32)
+		} else {
+			read_u32(data, 40) as u64
+		};
+		self.host_time = saved;
+		self.sync();
+	}
+}
+
+mod test {
+	#[cfg(test)]
+	fn cycle_rtc() -> super::Rtc {
+		let mut rtc = super::Rtc::create();
+		rtc.set_source(super::ClockSource::Cycles);
+		rtc
+	}
+
+	#[test]
+	fn test_cycle_clock() {
+		use super::*;
+		let mut rtc = cycle_rtc();
+		rtc.tick(CYCLES_PER_SECOND - 4);
+		assert_eq!(rtc.seconds, 0);
+		rtc.tick(4);
+		assert_eq!(rtc.seconds, 1);
+		rtc.tick(CYCLES_PER_SECOND * 3600);
+		assert_eq!(rtc.hours, 1);
+		assert_eq!(rtc.seconds, 1);
+	}
+
+	#[test]
+	fn test_rollover() {
+		let mut rtc = cycle_rtc();
+		rtc.write(0x08, 59);
+		rtc.write(0x09, 59);
+		rtc.write(0x0A, 23);
+		rtc.write(0x0B, 0xFF);
+		rtc.write(0x0C, 0x01);
+		rtc.advance(1);
+		assert_eq!(rtc.seconds, 0);
+		assert_eq!(rtc.minutes, 0);
+		assert_eq!(rtc.hours, 0);
+		assert_eq!(rtc.days, 0);
+		assert!(rtc.carry);
+	}
+
+	#[test]
+	fn test_out_of_range() {
+		let mut rtc = cycle_rtc();
+		rtc.write(0x08, 62);
+		rtc.advance(1);
+		assert_eq!(rtc.seconds, 63);
+		assert_eq!(rtc.minutes, 0);
+		rtc.advance(1);
+		assert_eq!(rtc.seconds, 0);
+		assert_eq!(rtc.minutes, 0);
+		rtc.advance(61);
+		assert_eq!(rtc.seconds, 1);
+		assert_eq!(rtc.minutes, 1);
+	}
+
+	#[test]
+	fn test_halt() {
+		use super::*;
+		let mut rtc = cycle_rtc();
+		rtc.write(0x0C, 0x40);
+		rtc.tick(CYCLES_PER_SECOND * 5);
+		assert_eq!(rtc.seconds, 0);
+		rtc.write(0x0C, 0x00);
+		rtc.tick(CYCLES_PER_SECOND * 5);
+		assert_eq!(rtc.seconds, 5);
+	}
+
+	#[test]
+	fn test_latch() {
+		use super::*;
+		let mut rtc = cycle_rtc();
+		rtc.tick(CYCLES_PER_SECOND * 10);
+		assert_eq!(rtc.read(0x08), 0);
+		rtc.write_latch(0x01);
+		assert_eq!(rtc.read(0x08), 0);
+		rtc.write_latch(0x00);
+		rtc.write_latch(0x01);
+		assert_eq!(rtc.read(0x08), 10);
+		rtc.tick(CYCLES_PER_SECOND * 10);
+		assert_eq!(rtc.read(0x08), 10);
+	}
+
+	#[test]
+	fn test_save_round_trip() {
+		let mut rtc = cycle_rtc();
+		rtc.write(0x0A, 5);
+		rtc.write(0x0B, 0x34);
+		rtc.write(0x0C, 0xC1);
+		let data = rtc.save_bytes();
+		assert_eq!(data.len(), super::SAVE_SIZE);
+		let mut loaded = cycle_rtc();
+		loaded.load_bytes(&data);
+		assert_eq!(loaded.hours, 5);
+		assert_eq!(loaded.days, 0x134);
+		assert!(loaded.halt);
+		assert!(loaded.carry);
+		assert_eq!(loaded.latched[2], 5);
+	}
+
+	#[test]
+	fn test_save_timestamp() {
+		use super::*;
+		let mut rtc = cycle_rtc();
+		rtc.host_time = 0;
+		rtc.tick(CYCLES_PER_SECOND * 2);
+		let data = rtc.save_bytes();
+		let saved = read_u32(&data, 40) as u64 | ((read_u32(&data, 44) as u64) << 32);
+		assert!(saved > 0);
+		assert_eq!(saved, rtc.host_time);
+		assert_eq!(rtc.seconds, 2);
+	}
+}
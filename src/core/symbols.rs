@@ -0,0 +1,196 @@
+// Labels from RGBDS .sym files, one "bank:addr Label" per line
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+
+use super::error::EmuError;
+use super::mbc::Mbc;
+use super::memory::Memory;
+
+#[derive(Default)]
+pub struct Symbols {
+	pub labels: BTreeMap<(usize, u16), String>,
+	pub addrs: HashMap<String, (usize, u16)>,
+}
+
+// The bank RGBDS would number the region at addr with, given what's mapped right now
+pub fn bank_for(mem:&Memory, addr:u16) -> usize {
+	match addr {
+		0x0000 ..= 0x7FFF | 0xA000 ..= 0xBFFF => mem.rom.mbc.bank_at(addr),
+		// WRAMX is bank 1 on the DMG
+		0xD000 ..= 0xDFFF => 1,
+		_ => 0,
+	}
+}
+
+// Start of the memory map region holding addr: ROM0, ROMX, VRAM, SRAM, WRAM0, WRAMX, echo, OAM, unusable, IO, HRAM and IE
+fn region_start(addr:u16) -> u16 {
+	match addr {
+		0x0000 ..= 0x3FFF => 0x0000,
+		0x4000 ..= 0x7FFF => 0x4000,
+		0x8000 ..= 0x9FFF => 0x8000,
+		0xA000 ..= 0xBFFF => 0xA000,
+		0xC000 ..= 0xCFFF => 0xC000,
+		0xD000 ..= 0xDFFF => 0xD000,
+		0xE000 ..= 0xFDFF => 0xE000,
+		0xFE00 ..= 0xFE9F => 0xFE00,
+		0xFEA0 ..= 0xFEFF => 0xFEA0,
+		0xFF00 ..= 0xFF7F => 0xFF00,
+		0xFF80 ..= 0xFFFE => 0xFF80,
+		0xFFFF => 0xFFFF,
+	}
+}
+
+// Whether addr is switchable ROM or cartridge RAM, where the same address holds different things per bank
+pub fn is_banked(mem:&Memory, addr:u16) -> bool {
+	match mem.rom.mbc {
+		Mbc::NoMbc => false,
+		_ => matches!(addr, 0x4000 ..= 0x7FFF | 0xA000 ..= 0xBFFF),
+	}
+}
+
+impl Symbols {
+	pub fn create() -> Symbols {
+		Symbols::default()
+	}
+
+	// Malformed lines are skipped, so files with extra sections still load
+	pub fn parse(text:&str) -> Symbols {
+		let mut symbols = Symbols::create();
+		for line in text.lines() {
+			let line = line.split(';').next().unwrap_or("").trim();
+			let mut parts = line.split_whitespace();
+			let (location, name) = match (parts.next(), parts.next()) {
+				(Some(location), Some(name)) => (location, name),
+				_ => continue,
+			};
+			let mut location = location.splitn(2, ':');
+			let bank = location.next().and_then(|b| usize::from_str_radix(b, 16).ok());
+			let addr = location.next().and_then(|a| u16::from_str_radix(a, 16).ok());
+			if let (Some(bank), Some(addr)) = (bank, addr) {
+				symbols.insert(bank, addr, name);
+			}
+		}
+		symbols
+	}
+
+	pub fn load_file(path:&str) -> Result<Symbols, EmuError> {
+		let mut text = String::new();
+		File::open(path)?.read_to_string(&mut text)?;
+		Ok(Symbols::parse(&text))
+	}
+
+	pub fn insert(&mut self, bank:usize, addr:u16, name:&str) {
+		// Keep the first label at an address, later ones are usually local aliases
+		self.labels.entry((bank, addr)).or_insert_with(|| name.to_string());
+		self.addrs.insert(name.to_string(), (bank, addr));
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.labels.is_empty()
+	}
+
+	// The label at addr in whichever bank is mapped there. Unbanked regions also take bank 0
+	// labels, as rgblink -t and -w number 0x4000-0x7FFF and WRAMX that way.
+	pub fn lookup(&self, mem:&Memory, addr:u16) -> Option<&str> {
+		let bank = bank_for(mem, addr);
+		let label = match self.labels.get(&(bank, addr)) {
+			None if !is_banked(mem, addr) => self.labels.get(&(0, addr)),
+			label => label,
+		};
+		label.map(|name| name.as_str())
+	}
+
+	// The closest label at or before addr in bank, without reaching back into another region
+	fn closest(&self, bank:usize, addr:u16) -> Option<(u16, &String)> {
+		self.labels.range((bank, region_start(addr)) ..= (bank, addr)).next_back()
+			.map(|(&(_, found), name)| (found, name))
+	}
+
+	// "Label" or "Label+$off" for the closest label at or before addr, falling back to bank 0 like lookup
+	pub fn describe(&self, mem:&Memory, addr:u16) -> Option<String> {
+		let bank = bank_for(mem, addr);
+		let (found, name) = match self.closest(bank, addr) {
+			None if !is_banked(mem, addr) => self.closest(0, addr)?,
+			found => found?,
+		};
+		if found == addr {
+			Some(name.clone())
+		} else {
+			Some(format!("{}+${:X}", name, addr - found))
+		}
+	}
+
+	pub fn find(&self, name:&str) -> Option<(usize, u16)> {
+		self.addrs.get(name).cloned()
+	}
+}
+
+mod test {
+	#[cfg(test)]
+	const SYM: &str = "; File generated by rgblink
+00:0150 Start
+00:0150 Start.alias
+00:0200 Main
+01:4000 UpdateSprites
+02:4000 LoadLevel
+00:c100 wScore
+";
+
+	#[test]
+	fn test_parse() {
+		use super::*;
+		let symbols = Symbols::parse(SYM);
+		assert_eq!(symbols.labels.len(), 5);
+		assert_eq!(symbols.find("LoadLevel"), Some((2, 0x4000)));
+		assert_eq!(symbols.find("Start.alias"), Some((0, 0x0150)));
+		assert_eq!(symbols.find("Nope"), None);
+	}
+
+	#[test]
+	fn test_bank_lookup() {
+		use super::*;
+		let symbols = Symbols::parse(SYM);
+		let mut mem = Memory::create_memory();
+		let mut data = vec![0; 0x10000];
+		data[0x0147] = 0x11;
+		data[0x0148] = 0x01;
+		mem.rom.load_data(data).unwrap();
+		assert_eq!(symbols.lookup(&mem, 0x0150), Some("Start"));
+		assert_eq!(symbols.lookup(&mem, 0x4000), Some("UpdateSprites"));
+		mem.set_mem(0x2000, 0x02);
+		assert_eq!(symbols.lookup(&mem, 0x4000), Some("LoadLevel"));
+		assert_eq!(symbols.lookup(&mem, 0xC100), Some("wScore"));
+		assert_eq!(symbols.describe(&mem, 0x4010), Some("LoadLevel+$10".to_string()));
+		assert_eq!(symbols.describe(&mem, 0x0201), Some("Main+$1".to_string()));
+		assert_eq!(symbols.describe(&mem, 0x0100), None);
+	}
+
+	#[test]
+	fn test_no_fallback_when_banked() {
+		use super::*;
+		let symbols = Symbols::parse("00:4000 Tiny\n00:d000 wBuffer\n");
+		let mut mem = Memory::create_memory();
+		assert_eq!(symbols.lookup(&mem, 0x4000), Some("Tiny"));
+		assert_eq!(symbols.lookup(&mem, 0xD000), Some("wBuffer"));
+		let mut data = vec![0; 0x10000];
+		data[0x0147] = 0x11;
+		data[0x0148] = 0x01;
+		mem.rom.load_data(data).unwrap();
+		assert_eq!(symbols.lookup(&mem, 0x4000), None);
+		assert_eq!(symbols.lookup(&mem, 0xD000), Some("wBuffer"));
+	}
+
+	#[test]
+	fn test_describe_regions() {
+		use super::*;
+		let symbols = Symbols::parse("00:c100 wScore\n00:9800 vMap\n00:d000 wBuffer\n");
+		let mem = Memory::create_memory();
+		assert_eq!(symbols.describe(&mem, 0xC102), Some("wScore+$2".to_string()));
+		assert_eq!(symbols.describe(&mem, 0xFF90), None);
+		assert_eq!(symbols.describe(&mem, 0xA010), None);
+		// WRAMX labelled as bank 0 by rgblink -w still describes, the same as lookup
+		assert_eq!(symbols.describe(&mem, 0xD004), Some("wBuffer+$4".to_string()));
+	}
+}
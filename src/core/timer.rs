@@ -1,16 +1,172 @@
+use super::interrupts::{Interrupts, Interrupt};
+
 pub struct Timer {
-	pub cycles: u64,
+	pub counter: u16, // Internal divider, DIV is the upper byte
+	pub tima: u8,
+	pub tma: u8,
+	pub tac: u8,
+	pub overflow_pending: bool, // TIMA overflowed last cycle and reads 0 until reloaded
+	pub reloading: bool, // TIMA was reloaded from TMA this cycle
 }
 
 impl Timer {
 	pub fn create() -> Timer {
 		Timer {
-			cycles: 0
+			counter: 0xABCC,
+			tima: 0,
+			tma: 0,
+			tac: 0,
+			overflow_pending: false,
+			reloading: false
+		}
+	}
+
+	pub fn step(&mut self, cycles:u64, int:&mut Interrupts){
+		for _ in 0 .. cycles / 4 {
+			self.tick(int);
+		}
+	}
+
+	// Advances a single M-cycle
+	fn tick(&mut self, int:&mut Interrupts) {
+		self.reloading = false;
+		if self.overflow_pending {
+			self.overflow_pending = false;
+			self.tima = self.tma;
+			self.reloading = true;
+			int.request(Interrupt::Timer);
+		}
+		let before = self.signal();
+		self.counter = self.counter.wrapping_add(4);
+		if before && !self.signal() {
+			self.inc_tima();
 		}
 	}
 
-	pub fn step(&mut self, cycles:u64){
-		self.cycles += cycles;
+	// The divider bit TIMA is clocked from, gated by the enable bit
+	fn signal(&self) -> bool {
+		let bit = match self.tac & 0x03 {
+			0x0 => 9,
+			0x1 => 3,
+			0x2 => 5,
+			_ => 7,
+		};
+		self.tac & 0x04 != 0 && (self.counter >> bit) & 0x1 == 0x1
 	}
 
-}
\ No newline at end of file
+	fn inc_tima(&mut self) {
+		let (res, overflow) = self.tima.overflowing_add(1);
+		self.tima = res;
+		if overflow {
+			log!(Timer, Debug, "TIMA overflowed, reloading {:02X}", self.tma);
+			self.overflow_pending = true;
+		}
+	}
+
+	pub fn get_mem(&self, loc:u16) -> u8 {
+		match loc {
+			0xFF04 => (self.counter >> 8) as u8,
+			0xFF05 => self.tima,
+			0xFF06 => self.tma,
+			_ => self.tac | 0xF8,
+		}
+	}
+
+	pub fn set_mem(&mut self, loc:u16, val:u8) {
+		let before = self.signal();
+		match loc {
+			0xFF04 => self.counter = 0,
+			0xFF05 => {
+				// A write during the delay cancels the reload, one during the reload is lost
+				if !self.reloading {
+					self.tima = val;
+					self.overflow_pending = false;
+				}
+			}
+			0xFF06 => {
+				self.tma = val;
+				if self.reloading {
+					self.tima = val;
+				}
+			}
+			_ => self.tac = val & 0x07,
+		}
+		// Resetting DIV or changing TAC can drop the selected bit, which TIMA sees as an edge
+		if before && !self.signal() {
+			self.inc_tima();
+		}
+	}
+}
+
+mod test {
+	#[test]
+	fn test_div() {
+		let mut timer = super::Timer::create();
+		let mut int = super::Interrupts::create();
+		timer.set_mem(0xFF04, 0x55);
+		assert_eq!(timer.get_mem(0xFF04), 0);
+		timer.step(256, &mut int);
+		assert_eq!(timer.get_mem(0xFF04), 1);
+		timer.step(256 * 3, &mut int);
+		assert_eq!(timer.get_mem(0xFF04), 4);
+	}
+
+	#[test]
+	fn test_tima_rate() {
+		let mut timer = super::Timer::create();
+		let mut int = super::Interrupts::create();
+		timer.set_mem(0xFF04, 0);
+		timer.set_mem(0xFF07, 0x05); // Enabled, every 16 cycles
+		timer.step(16 * 10, &mut int);
+		assert_eq!(timer.get_mem(0xFF05), 10);
+		timer.set_mem(0xFF07, 0x01); // Disabled
+		timer.step(16 * 10, &mut int);
+		assert_eq!(timer.get_mem(0xFF05), 10);
+	}
+
+	#[test]
+	fn test_overflow_reload() {
+		use super::Interrupt;
+		let mut timer = super::Timer::create();
+		let mut int = super::Interrupts::create();
+		int.iflag = 0;
+		timer.set_mem(0xFF04, 0);
+		timer.set_mem(0xFF06, 0xF0);
+		timer.set_mem(0xFF05, 0xFF);
+		timer.set_mem(0xFF07, 0x05);
+		timer.step(16, &mut int);
+		assert_eq!(timer.get_mem(0xFF05), 0x00);
+		assert_eq!(int.iflag, 0);
+		timer.step(4, &mut int);
+		assert_eq!(timer.get_mem(0xFF05), 0xF0);
+		assert_eq!(int.iflag, Interrupt::Timer.bit());
+	}
+
+	#[test]
+	fn test_overflow_cancel() {
+		let mut timer = super::Timer::create();
+		let mut int = super::Interrupts::create();
+		int.iflag = 0;
+		timer.set_mem(0xFF04, 0);
+		timer.set_mem(0xFF06, 0xF0);
+		timer.set_mem(0xFF05, 0xFF);
+		timer.set_mem(0xFF07, 0x05);
+		timer.step(16, &mut int);
+		timer.set_mem(0xFF05, 0x42);
+		timer.step(4, &mut int);
+		assert_eq!(timer.get_mem(0xFF05), 0x42);
+		assert_eq!(int.iflag, 0);
+	}
+
+	#[test]
+	fn test_div_write_glitch() {
+		let mut timer = super::Timer::create();
+		let mut int = super::Interrupts::create();
+		timer.set_mem(0xFF04, 0);
+		timer.set_mem(0xFF07, 0x05);
+		timer.step(8, &mut int); // Bit 3 is now set
+		assert_eq!(timer.get_mem(0xFF05), 0);
+		timer.set_mem(0xFF04, 0);
+		assert_eq!(timer.get_mem(0xFF05), 1);
+	}
+}
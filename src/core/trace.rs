@@ -0,0 +1,73 @@
+// One line per instruction in the Gameboy Doctor format, for diffing against other emulators
+
+use std::io;
+use std::io::Write;
+
+use super::memory::Memory;
+use super::registers::Registers;
+use super::symbols::Symbols;
+
+pub struct Trace {
+	pub out: Box<dyn Write>,
+	pub labels: bool, // Appends the label at PC, which stops lines matching reference logs exactly
+}
+
+// State before the instruction at PC runs, e.g.
+// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+pub fn format_line(reg:&Registers, mem:&Memory) -> String {
+	let pc = reg.pc;
+	format!("A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+		reg.a, reg.f, reg.b, reg.c, reg.d, reg.e, reg.h, reg.l, reg.sp, pc,
+		mem.peek(pc), mem.peek(pc.wrapping_add(1)), mem.peek(pc.wrapping_add(2)), mem.peek(pc.wrapping_add(3)))
+}
+
+impl Trace {
+	pub fn create(out:Box<dyn Write>) -> Trace {
+		Trace {
+			out,
+			labels: false
+		}
+	}
+
+	pub fn line(&self, reg:&Registers, mem:&Memory, symbols:&Symbols) -> String {
+		if !self.labels {
+			return format_line(reg, mem);
+		}
+		match symbols.describe(mem, reg.pc) {
+			Some(label) => format!("{} ; {}", format_line(reg, mem), label),
+			None => format_line(reg, mem),
+		}
+	}
+
+	pub fn write(&mut self, reg:&Registers, mem:&Memory, symbols:&Symbols) -> io::Result<()> {
+		let line = self.line(reg, mem, symbols);
+		writeln!(self.out, "{}", line)
+	}
+}
+
+mod test {
+	#[test]
+	fn test_format_line() {
+		use super::*;
+		let reg = Registers::load_defaults();
+		let mut mem = Memory::create_memory();
+		let mut data = vec![0; 0x8000];
+		data[0x0101] = 0xC3;
+		data[0x0102] = 0x13;
+		data[0x0103] = 0x02;
+		mem.rom.load_data(data).unwrap();
+		assert_eq!(format_line(&reg, &mem), "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02");
+	}
+
+	#[test]
+	fn test_labels() {
+		use super::*;
+		let reg = Registers::load_defaults();
+		let mem = Memory::create_memory();
+		let symbols = Symbols::parse("00:00FE Entry");
+		let mut trace = Trace::create(Box::new(Vec::new()));
+		assert!(trace.line(&reg, &mem, &symbols).ends_with("PCMEM:00,00,00,00"));
+		trace.labels = true;
+		assert!(trace.line(&reg, &mem, &symbols).ends_with("PCMEM:00,00,00,00 ; Entry+$2"));
+	}
+}
@@ -0,0 +1,79 @@
+// Watchpoints on CPU memory accesses, checked by Memory while an instruction runs
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+	Read,
+	Write,
+	Change, // A write that leaves a different value behind
+	Access, // Any read or write
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watchpoint {
+	pub start: u16,
+	pub end: u16, // Inclusive
+	pub kind: WatchKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchHit {
+	pub index: usize,
+	pub kind: WatchKind,
+	pub addr: u16,
+	pub pc: u16, // Start of the instruction that made the access
+	pub old: u8,
+	pub new: u8, // Same as old for reads
+}
+
+impl Watchpoint {
+	pub fn covers(&self, addr:u16) -> bool {
+		(self.start ..= self.end).contains(&addr)
+	}
+
+	fn triggers(&self, kind:WatchKind, old:u8, new:u8) -> bool {
+		match (self.kind, kind) {
+			(WatchKind::Read, WatchKind::Read) => true,
+			(WatchKind::Write, WatchKind::Write) => true,
+			(WatchKind::Change, WatchKind::Write) => old != new,
+			(WatchKind::Access, _) => true,
+			_ => false,
+		}
+	}
+}
+
+impl fmt::Display for WatchHit {
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Watchpoint {} ({:?}) at {:04X} by {:04X}: {:02X} -> {:02X}",
+			self.index, self.kind, self.addr, self.pc, self.old, self.new)
+	}
+}
+
+// The first watchpoint an access trips, kind being Read or Write
+pub fn check(watchpoints:&[Watchpoint], kind:WatchKind, addr:u16, pc:u16, old:u8, new:u8) -> Option<WatchHit> {
+	watchpoints.iter()
+		.position(|wp| wp.covers(addr) && wp.triggers(kind, old, new))
+		.map(|index| WatchHit { index, kind: watchpoints[index].kind, addr, pc, old, new })
+}
+
+mod test {
+	#[test]
+	fn test_check() {
+		use super::*;
+		let watchpoints = [
+			Watchpoint { start: 0xC000, end: 0xC0FF, kind: WatchKind::Change },
+			Watchpoint { start: 0xC010, end: 0xC010, kind: WatchKind::Write },
+			Watchpoint { start: 0xFF44, end: 0xFF44, kind: WatchKind::Read },
+			Watchpoint { start: 0xFF80, end: 0xFF80, kind: WatchKind::Access },
+		];
+		assert_eq!(check(&watchpoints, WatchKind::Write, 0xC020, 0x150, 1, 1), None);
+		assert_eq!(check(&watchpoints, WatchKind::Write, 0xC020, 0x150, 1, 2).unwrap().index, 0);
+		let hit = check(&watchpoints, WatchKind::Write, 0xC010, 0x150, 3, 3).unwrap();
+		assert_eq!(hit, WatchHit { index: 1, kind: WatchKind::Write, addr: 0xC010, pc: 0x150, old: 3, new: 3 });
+		assert_eq!(check(&watchpoints, WatchKind::Read, 0xC010, 0x150, 3, 3), None);
+		assert_eq!(check(&watchpoints, WatchKind::Read, 0xFF44, 0x150, 0x90, 0x90).unwrap().index, 2);
+		assert_eq!(check(&watchpoints, WatchKind::Read, 0xFF80, 0x150, 0, 0).unwrap().kind, WatchKind::Access);
+		assert_eq!(check(&watchpoints, WatchKind::Write, 0xFF80, 0x150, 0, 0).unwrap().kind, WatchKind::Access);
+	}
+}
@@ -1,19 +1,239 @@
 extern crate time;
+extern crate rustboy;
+#[cfg(unix)]
+extern crate libc;
 use time::PreciseTime;
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufWriter, Write};
+#[cfg(unix)]
+use std::mem;
+use std::path::Path;
+use std::process;
+#[cfg(unix)]
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-mod core;
+use rustboy::core::Core as gbcore;
+use rustboy::core::debugger::Debugger;
+use rustboy::core::disasm;
+use rustboy::core::gdb::GdbStub;
+use rustboy::core::log;
+use rustboy::core::symbols::Symbols;
 
-use core::Core as gbcore;
+// An explicit --sym file must load, otherwise a .sym next to the ROM is picked up if there is one
+fn load_symbols(core:&mut gbcore, rom_path:&str, sym_path:Option<String>) {
+    let explicit = sym_path.is_some();
+    let path = sym_path.unwrap_or_else(|| Path::new(rom_path).with_extension("sym").to_string_lossy().into_owned());
+    match Symbols::load_file(&path) {
+        Ok(symbols) => core.symbols = symbols,
+        Err(e) => if explicit {
+            println!("Couldn't load symbols from {}: {}", path, e);
+        }
+    }
+}
+
+// Prints every instruction in one ROM bank, as it appears at 0x0000 or 0x4000
+fn disasm(mut args:env::Args) {
+    let (rom_path, bank) = match (args.next(), args.next().map(|b| usize::from_str_radix(b.trim_start_matches("0x"), 16))) {
+        (Some(path), None) => (path, 0),
+        (Some(path), Some(Ok(bank))) => (path, bank),
+        _ => {
+            println!("Usage: rustBoy disasm <rom> [bank in hex]");
+            process::exit(1);
+        }
+    };
+    let mut core = gbcore::new();
+    if let Err(e) = core.mem.rom.load_file(rom_path.clone()) {
+        println!("Couldn't load ROM: {}", e);
+        process::exit(1);
+    }
+    // The MBC would mask an out of range number and quietly show some other bank
+    let banks = core.mem.rom.header.rom_bytes().unwrap_or(0x8000) / 0x4000;
+    if bank >= banks {
+        println!("Bank {:X} is out of range, the ROM has {:X} banks", bank, banks);
+        process::exit(1);
+    }
+    load_symbols(&mut core, &rom_path, None);
+    let (start, end) = if bank == 0 { (0x0000, 0x3FFF) } else { (0x4000, 0x7FFF) };
+    core.mem.rom.mbc.map_rom_bank(bank);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for mut ins in disasm::disassemble(&core.mem, start, end) {
+        if let Some(label) = core.symbols.lookup(&core.mem, ins.addr) {
+            let _ = writeln!(out, "{}:", label);
+        }
+        ins.symbolize(&core.mem, &core.symbols);
+        // Stop quietly when piped into something like head
+        if writeln!(out, "{:02X}:{}", bank, ins).is_err() {
+            return;
+        }
+    }
+}
+
+// Dirty save RAM is written back about this often so a crash loses little progress
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+fn save(core:&mut gbcore) {
+    if let Err(e) = core.mem.rom.flush() {
+        println!("Couldn't write save file: {}", e);
+    }
+}
+
+// Set by ^C so a running debugger drops back to its prompt instead of the process exiting
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Routes ^C to INTERRUPTED while alive and puts the previous handler back when dropped,
+// so a ^C at the idle prompt still exits. std has no signal API, and libc is already
+// in the build through time.
+struct CatchInterrupt {
+    #[cfg(unix)]
+    previous: libc::sigaction,
+}
+
+impl CatchInterrupt {
+    #[cfg(unix)]
+    fn install() -> CatchInterrupt {
+        extern "C" fn on_sigint(_:libc::c_int) {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+        }
+        // SAFETY: the handler only does an atomic store, which is async-signal-safe, and a
+        // zeroed sigaction is an empty mask with no flags
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            let mut previous: libc::sigaction = mem::zeroed();
+            libc::sigaction(libc::SIGINT, &action, &mut previous);
+            CatchInterrupt { previous }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn install() -> CatchInterrupt {
+        CatchInterrupt {}
+    }
+}
+
+#[cfg(unix)]
+impl Drop for CatchInterrupt {
+    fn drop(&mut self) {
+        // SAFETY: restores the action saved by install
+        unsafe {
+            libc::sigaction(libc::SIGINT, &self.previous, ptr::null_mut());
+        }
+    }
+}
+
+fn run_debugger(core:&mut gbcore) {
+    let mut debugger = Debugger::create();
+    debugger.interrupted = Box::new(|| INTERRUPTED.swap(false, Ordering::SeqCst));
+    let stdin = io::stdin();
+    println!("{}", disasm::decode(&core.mem, core.reg.pc));
+    while !debugger.quit {
+        print!("(rustboy) ");
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        // A ^C that landed after the last poll shouldn't cut short the next command
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        let result = {
+            let _catch = CatchInterrupt::install();
+            debugger.execute(core, &line)
+        };
+        match result {
+            Ok(ref out) if out.is_empty() => {}
+            Ok(out) => println!("{}", out),
+            Err(e) => println!("{}", e),
+        }
+        save(core);
+    }
+}
 
 fn main() {
+    // e.g. RUSTBOY_LOG=warn,cpu=trace
+    if let Ok(spec) = env::var("RUSTBOY_LOG") {
+        if let Err(e) = log::configure(&spec) {
+            println!("{}", e);
+        }
+    }
+
+    let mut rom_path = "../pyGBE/ROMS/tetris.gb".to_string();
+    let mut trace_path = None;
+    let mut stub_ly = false;
+    let mut debug = false;
+    let mut gdb_addr = None;
+    let mut sym_path = None;
+    let mut trace_labels = false;
+    let mut args = env::args();
+    args.next();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "disasm" => return disasm(args),
+            "--trace" => trace_path = args.next(),
+            "--stub-ly" => stub_ly = true,
+            "--debug" => debug = true,
+            "--gdb" => gdb_addr = args.next(),
+            "--sym" => sym_path = args.next(),
+            "--trace-labels" => trace_labels = true,
+            _ => rom_path = arg,
+        }
+    }
+
     let mut test_core = gbcore::new();
 
-    test_core.mem.rom.load_file("../pyGBE/ROMS/tetris.gb".to_string());
+    if let Err(e) = test_core.mem.rom.load_file(rom_path.clone()) {
+        println!("Couldn't load ROM: {}", e);
+        process::exit(1);
+    }
+    load_symbols(&mut test_core, &rom_path, sym_path);
+    for mismatch in test_core.mem.rom.validate() {
+        println!("Warning: {}", mismatch);
+    }
+    if let Some(path) = trace_path {
+        match File::create(&path) {
+            Ok(file) => {
+                test_core.trace_to(Box::new(BufWriter::new(file)), stub_ly);
+                if let Some(ref mut trace) = test_core.trace {
+                    trace.labels = trace_labels;
+                }
+            }
+            Err(e) => {
+                println!("Couldn't create trace file {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
 
     let start = PreciseTime::now();
-    while test_core.reg.pc != 0x02D6{
-    	test_core.step();
+    if let Some(addr) = gdb_addr {
+        println!("Waiting for GDB on {}", addr);
+        if let Err(e) = GdbStub::create().listen(&mut test_core, &addr) {
+            println!("GDB connection failed: {}", e);
+        }
+    } else if debug {
+        run_debugger(&mut test_core);
+    } else {
+        let mut last_save = Instant::now();
+        let mut steps: u32 = 0;
+        loop {
+            if let Err(e) = test_core.step().and(test_core.status()) {
+                println!("{}", e);
+                break;
+            }
+            // Checking the clock every step would dominate the loop
+            steps = steps.wrapping_add(1);
+            if steps & 0xFFFF == 0 && last_save.elapsed() >= SAVE_INTERVAL {
+                save(&mut test_core);
+                last_save = Instant::now();
+            }
+        }
     }
     let end = PreciseTime::now();
     println!("{} seconds.", start.to(end));
+    save(&mut test_core);
 }